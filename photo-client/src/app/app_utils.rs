@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use shared::Tree;
+use shared::{DeviceApproval, FileMetadata, PendingDevice, RepoAccess, RepoQuota, RepoQuotaUpdate, RepoStats, Role, SearchQuery, SearchResults, Tree};
 
 pub enum Commands {
     Log(String),
@@ -17,6 +17,24 @@ pub enum Commands {
     GetSubDir(String),
     Notify(String),
     DiscoverUntracked(String),
+    RestoreRepository(String, String, String),
+    DownloadFile(String, String, String), // repo name, relative path and the directory to save it in
+    GetStoreStats,
+    PinCertificate(String, String),
+    PinServerKey(String, String),
+    SetRepoAccess(RepoAccess),
+    GetFileMetadata(String, String),
+    PostFileMetadata(FileMetadata),
+    GetThumbnail(String, String, String, u32), // repo name, relative path, content hash and size
+    PostThumbnail(String, u32, Option<Vec<u8>>), // content hash, size and the jpeg, None when there's no thumbnail
+    Search(SearchQuery),
+    PostSearchResults(SearchResults),
+    GetRepoStats(String),
+    PostRepoStats(RepoStats),
+    SetRepoQuota(RepoQuotaUpdate),
+    GetPendingDevices,
+    PostPendingDevices(Vec<PendingDevice>),
+    ApproveDevice(DeviceApproval),
 }
 
 #[derive(PartialEq)]
@@ -38,6 +56,120 @@ impl std::fmt::Display for ConnectionStatus {
         }
     }
 }
+// the longest edge of the thumbnails shown in the file explorer and of the previews shown in the viewer
+pub const THUMBNAIL_SIZE: u32 = 256;
+pub const PREVIEW_SIZE: u32 = 1024;
+
+// a preview in the file explorer's grid, keyed by content hash
+pub enum Thumbnail {
+    Requested,
+    Loaded(egui::TextureHandle),
+    Unavailable, // not an image, or the server couldn't make a thumbnail of it
+}
+
+// the file open in the viewer window
+pub struct Viewer {
+    pub relative_path: String,
+    pub hash: String,
+    pub preview: Thumbnail,
+    pub zoom: f32, // 1 fits the whole image in the window
+    pub pan: egui::Vec2, // offset of the image's centre from the window's
+}
+
+impl Viewer {
+    pub fn new(relative_path: String, hash: String) -> Self {
+        Viewer {
+            relative_path,
+            hash,
+            preview: Thumbnail::Requested,
+            zoom: 1.0,
+            pan: egui::Vec2::ZERO,
+        }
+    }
+}
+
+// what the search box above the file explorer holds, sizes are in megabytes and dates are YYYY-MM-DD
+#[derive(Default)]
+pub struct SearchForm {
+    pub name: String,
+    pub show_filters: bool,
+    pub extension: String,
+    pub min_size: String,
+    pub max_size: String,
+    pub captured_from: String,
+    pub captured_to: String,
+    pub camera: String,
+}
+
+fn parse_megabytes(text: &str) -> Result<Option<u64>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    text.parse::<f64>()
+        .map(|megabytes| Some((megabytes * 1024.0 * 1024.0) as u64))
+        .map_err(|_| format!("{} is not a size in megabytes", text))
+}
+
+// midnight at the start of the date in the local timezone, in unix seconds
+fn parse_date(text: &str, days_after: u64) -> Result<Option<i64>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.checked_add_days(chrono::Days::new(days_after)))
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|time| time.and_local_timezone(chrono::Local).earliest())
+        .map(|time| Some(time.timestamp()))
+        .ok_or_else(|| format!("{} is not a date like 2024-06-30", text))
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() { None } else { Some(text.to_string()) }
+}
+
+impl SearchForm {
+    pub fn query(&self, repo_name: &str) -> Result<SearchQuery, String> {
+        Ok(SearchQuery {
+            repo_name: Some(repo_name.to_string()),
+            name: self.name.trim().to_string(),
+            extension: non_empty(&self.extension),
+            min_size: parse_megabytes(&self.min_size)?,
+            max_size: parse_megabytes(&self.max_size)?,
+            captured_after: parse_date(&self.captured_from, 0)?,
+            // the end date is included, so the search runs up to the midnight after it
+            captured_before: parse_date(&self.captured_to, 1)?,
+            camera: non_empty(&self.camera),
+            page: 0,
+            page_size: 0,
+        })
+    }
+}
+
+// the quota editor in the statistics panel, empty fields are unlimited and the size is in megabytes
+#[derive(Default)]
+pub struct QuotaForm {
+    pub max_files: String,
+    pub max_size: String,
+}
+
+impl QuotaForm {
+    pub fn quota(&self) -> Result<RepoQuota, String> {
+        let max_files = self.max_files.trim();
+        Ok(RepoQuota {
+            max_bytes: parse_megabytes(&self.max_size)?,
+            max_files: if max_files.is_empty() {
+                None
+            } else {
+                Some(max_files.parse().map_err(|_| format!("{} is not a number of files", max_files))?)
+            },
+        })
+    }
+}
+
 pub struct FileSystemEntry {
     pub name: String,
     pub is_directory: bool,
@@ -54,6 +186,19 @@ pub struct UiState {
     pub subdir_contents:Option<Vec<FileSystemEntry>>,
     pub tree: Option<Tree>,
     pub notification: Option<String>,
+    pub restore_destination: String,
+    pub share_user_name: String,
+    pub share_role: Role,
+    pub file_metadata: Option<FileMetadata>, // the file open in the viewer
+    pub viewer: Option<Viewer>,
+    pub search: SearchForm,
+    pub search_query: Option<SearchQuery>, // the last search sent, kept to ask for other pages
+    pub search_results: Option<SearchResults>,
+    pub thumbnail_grid: bool,
+    pub thumbnails: HashMap<String, Thumbnail>,
+    pub repo_stats: Option<RepoStats>, // the selected repository's, from the server's catalog
+    pub quota: QuotaForm,
+    pub pending_devices: Vec<PendingDevice>, // devices waiting to join this user's account
 }
 
 impl Default for UiState {
@@ -70,6 +215,19 @@ impl Default for UiState {
             subdir_contents: None,
             tree: None,
             notification: None,
+            restore_destination: String::new(),
+            share_user_name: String::new(),
+            share_role: Role::Reader,
+            file_metadata: None,
+            viewer: None,
+            search: SearchForm::default(),
+            search_query: None,
+            search_results: None,
+            thumbnail_grid: false,
+            thumbnails: HashMap::new(),
+            repo_stats: None,
+            quota: QuotaForm::default(),
+            pending_devices: Vec::new(),
         }
     }
 }
@@ -86,6 +244,18 @@ pub struct ClientConfig {
     pub server_address: String,
     pub server_storage_directory: String,
     pub repo_config: HashMap<String, RepoConfig>,
+    #[serde(default)]
+    pub use_tls: bool,
+    #[serde(default)]
+    pub pinned_certificates: HashMap<String, String>, // server address -> certificate fingerprint, pinned on first connect
+    #[serde(default)]
+    pub device_name: String,
+    #[serde(default)]
+    pub user_name: String, // the account the server enrolls this device under
+    #[serde(default)]
+    pub pinned_server_keys: HashMap<String, String>, // server address -> server public key, pinned on first connect
+    #[serde(skip)]
+    pub enrollment_code: String, // only needed until the server has enrolled this device
 }
 
 impl ClientConfig {
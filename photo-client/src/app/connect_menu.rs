@@ -2,6 +2,7 @@ use egui::{Color32, RichText, Frame};
 use super::{Commands, ConnectionStatus, App};
 use std::sync::mpsc;
 use crate::client::Client;
+use shared::DeviceApproval;
 
 impl App {
     fn connect_to_server (&mut self, ui:&mut egui::Ui) {
@@ -57,6 +58,46 @@ impl App {
     fn connect_config(&mut self, ui:&mut egui::Ui) {
         ui.label("Server Address:");
         ui.text_edit_singleline(&mut self.config.server_address);
+        ui.horizontal(|ui| {
+            ui.label("Device name:");
+            ui.text_edit_singleline(&mut self.config.device_name);
+            if !self.config.pinned_server_keys.contains_key(&self.config.server_address) {
+                ui.label("User:");
+                ui.text_edit_singleline(&mut self.config.user_name);
+                ui.label("Enrollment code:");
+                ui.text_edit_singleline(&mut self.config.enrollment_code);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::Checkbox::new(&mut self.config.use_tls, RichText::new("Use TLS").italics()));
+            if let Some(fingerprint) = self.config.pinned_certificates.get(&self.config.server_address) {
+                ui.label(RichText::new(format!("pinned certificate {}", &fingerprint[..std::cmp::min(16, fingerprint.len())])).weak());
+                if self.ui.connection_status == ConnectionStatus::Disconnected && ui.button("Forget certificate").clicked() {
+                    let server_address = self.config.server_address.clone();
+                    self.config.pinned_certificates.remove(&server_address);
+                }
+            }
+        });
+    }
+
+    // devices that presented the enrollment code under this user, they can't connect until one is approved here
+    fn pending_devices(&mut self, ui:&mut egui::Ui) {
+        let mut approval = None;
+        for device in &self.ui.pending_devices {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} wants to join {}", device.device_name, device.user_name))
+                    .on_hover_text(&device.public_key);
+                if ui.button("Approve").clicked() {
+                    approval = Some(DeviceApproval { public_key: device.public_key.clone(), approve: true });
+                }
+                if ui.button("Reject").clicked() {
+                    approval = Some(DeviceApproval { public_key: device.public_key.clone(), approve: false });
+                }
+            });
+        }
+        if let (Some(approval), Some(cli_tx)) = (approval, &self.cli_tx) {
+            cli_tx.send(Commands::ApproveDevice(approval)).unwrap();
+        }
     }
 
     pub fn connect_menu(&mut self, ui:&mut egui::Ui) {
@@ -107,10 +148,21 @@ impl App {
 
                     self.config.save_to_file(self.config_path.to_str().unwrap());
                 }
+                if ui.button("Storage stats").clicked() {
+                    if let Some(cli_tx) = &self.cli_tx {
+                        cli_tx.send(Commands::GetStoreStats).unwrap()
+                    }
+                }
+                if ui.button("Pending devices").clicked() {
+                    if let Some(cli_tx) = &self.cli_tx {
+                        cli_tx.send(Commands::GetPendingDevices).unwrap()
+                    }
+                }
                 if let Some(notification) = &mut self.ui.notification {
                     ui.label(notification.to_string());
                 }
             });
+            self.pending_devices(ui);
         }
     }
 }
\ No newline at end of file
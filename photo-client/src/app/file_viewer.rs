@@ -0,0 +1,218 @@
+use egui::{pos2, Color32, Key, Rect, RichText, Sense};
+use shared::{media_type, FileMetadata};
+use super::{App, Commands, Thumbnail, Viewer, PREVIEW_SIZE};
+use super::repository_menu::entry_path;
+
+// how far the viewer zooms relative to fitting the whole image in the window
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 8.0;
+const ZOOM_STEP: f32 = 1.25;
+
+// unix seconds from the server shown in the local timezone
+pub fn format_time(seconds: i64) -> String {
+    chrono::DateTime::from_timestamp(seconds, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// label and value for every field the server knows, fields the file doesn't record are left out
+fn metadata_rows(metadata: &FileMetadata) -> Vec<(&'static str, String)> {
+    let media = &metadata.media;
+    let mut rows = vec![
+        ("Size", format!("{} bytes", metadata.size)),
+        ("Hash", metadata.hash.clone()),
+        ("Uploaded", format_time(metadata.uploaded)),
+    ];
+    match media.captured {
+        Some(captured) => rows.push(("Captured", format_time(captured))),
+        None => rows.push(("Created", format_time(metadata.created))),
+    }
+    if !metadata.device.is_empty() {
+        rows.push(("Device", metadata.device.clone()));
+    }
+    let camera = [media.camera_make.clone(), media.camera_model.clone()].into_iter().flatten().collect::<Vec<String>>().join(" ");
+    if !camera.is_empty() {
+        rows.push(("Camera", camera));
+    }
+    if let Some(lens) = &media.lens {
+        rows.push(("Lens", lens.clone()));
+    }
+    let exposure = [
+        media.exposure_time.as_ref().map(|t| format!("{} s", t)),
+        media.f_number.map(|f| format!("f/{:.1}", f)),
+        media.iso.map(|iso| format!("ISO {}", iso)),
+        media.focal_length.map(|mm| format!("{:.0} mm", mm)),
+    ].into_iter().flatten().collect::<Vec<String>>().join("  ");
+    if !exposure.is_empty() {
+        rows.push(("Exposure", exposure));
+    }
+    if let (Some(width), Some(height)) = (media.width, media.height) {
+        rows.push(("Dimensions", format!("{} x {}", width, height)));
+    }
+    if let Some(orientation) = media.orientation {
+        rows.push(("Orientation", orientation.to_string()));
+    }
+    if let (Some(latitude), Some(longitude)) = (media.latitude, media.longitude) {
+        let location = match media.altitude {
+            Some(altitude) => format!("{:.6}, {:.6} at {:.0} m", latitude, longitude, altitude),
+            None => format!("{:.6}, {:.6}", latitude, longitude),
+        };
+        rows.push(("Location", location));
+    }
+    rows
+}
+
+// fits the preview in the space left of the metadata, dragging pans and scrolling zooms
+fn draw_preview(ui: &mut egui::Ui, viewer: &mut Viewer) {
+    let (area, response) = ui.allocate_exact_size(ui.available_size(), Sense::drag());
+    match &viewer.preview {
+        Thumbnail::Loaded(texture) => {
+            if response.hovered() {
+                let scroll = ui.input(|input| input.smooth_scroll_delta.y);
+                if scroll != 0.0 {
+                    viewer.zoom = (viewer.zoom * (1.0 + scroll * 0.002)).clamp(MIN_ZOOM, MAX_ZOOM);
+                }
+            }
+            viewer.pan += response.drag_delta();
+
+            let texture_size = texture.size_vec2();
+            let fit = (area.width() / texture_size.x).min(area.height() / texture_size.y);
+            let image_rect = Rect::from_center_size(area.center() + viewer.pan, texture_size * fit * viewer.zoom);
+            ui.painter().with_clip_rect(area).image(
+                texture.id(),
+                image_rect,
+                Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
+                Color32::WHITE,
+            );
+        }
+        Thumbnail::Requested => {
+            ui.put(area, egui::Spinner::new());
+        }
+        Thumbnail::Unavailable => {
+            ui.put(area, egui::Label::new(RichText::new("📄 No preview").size(24.0)));
+        }
+    }
+}
+
+impl App {
+    // shows a file in the viewer and asks the server for its preview and metadata
+    pub fn open_viewer(&mut self, relative_path: String) {
+        let Some(tree) = &self.ui.tree else {
+            return;
+        };
+        let Some(hash) = tree.files.get(&relative_path).map(|file| file.hash.clone()) else {
+            return;
+        };
+
+        let mut viewer = Viewer::new(relative_path.clone(), hash.clone());
+        let is_image = media_type(&relative_path) == "image";
+        if !is_image {
+            viewer.preview = Thumbnail::Unavailable;
+        }
+        if let Some(cli_tx) = &self.cli_tx {
+            cli_tx.send(Commands::GetFileMetadata(tree.name.clone(), relative_path.clone())).unwrap();
+            if is_image {
+                cli_tx.send(Commands::GetThumbnail(tree.name.clone(), relative_path, hash, PREVIEW_SIZE)).unwrap();
+            }
+        }
+        self.ui.file_metadata = None;
+        self.ui.viewer = Some(viewer);
+    }
+
+    // the file before or after the open one in the directory the explorer is showing
+    fn neighbouring_file(&self, step: isize) -> Option<String> {
+        let viewer = self.ui.viewer.as_ref()?;
+        let files: Vec<String> = self.ui.subdir_contents.as_ref()?.iter()
+            .filter(|entry| !entry.is_directory)
+            .map(|entry| entry_path(&self.ui.file_explorer_path, &entry.name))
+            .collect();
+        let index = files.iter().position(|relative_path| *relative_path == viewer.relative_path)?;
+        files.get(index.checked_add_signed(step)?).cloned()
+    }
+
+    pub fn file_viewer(&mut self, ctx: &egui::Context) {
+        let Some(viewer) = self.ui.viewer.as_mut() else {
+            return;
+        };
+        let mut open = true;
+        let mut step = 0;
+        let mut download = false;
+        let destination = self.ui.restore_destination.clone();
+
+        if !ctx.wants_keyboard_input() {
+            ctx.input(|input| {
+                if input.key_pressed(Key::ArrowLeft) {
+                    step = -1;
+                } else if input.key_pressed(Key::ArrowRight) {
+                    step = 1;
+                }
+            });
+        }
+
+        egui::Window::new(viewer.relative_path.clone())
+            .id(egui::Id::new("file_viewer"))
+            .open(&mut open)
+            .default_size([960.0, 720.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("◀ Previous").clicked() {
+                        step = -1;
+                    }
+                    if ui.button("Next ▶").clicked() {
+                        step = 1;
+                    }
+                    ui.separator();
+                    if ui.button("−").clicked() {
+                        viewer.zoom = (viewer.zoom / ZOOM_STEP).max(MIN_ZOOM);
+                    }
+                    if ui.button("+").clicked() {
+                        viewer.zoom = (viewer.zoom * ZOOM_STEP).min(MAX_ZOOM);
+                    }
+                    if ui.button("Fit").clicked() {
+                        viewer.zoom = 1.0;
+                        viewer.pan = egui::Vec2::ZERO;
+                    }
+                    ui.label(format!("{:.0}%", viewer.zoom * 100.0));
+                    ui.separator();
+                    // downloads go to the same place restores do
+                    download = ui.add_enabled(!destination.is_empty(), egui::Button::new("Download"))
+                        .on_hover_text(format!("Save to {}", destination))
+                        .on_disabled_hover_text("Enter a destination under Restore to first")
+                        .clicked();
+                });
+
+                egui::SidePanel::right("file_viewer_metadata")
+                    .resizable(false)
+                    .min_width(240.0)
+                    .show_inside(ui, |ui| {
+                        // metadata for the previous file may still be on its way
+                        match self.ui.file_metadata.as_ref().filter(|metadata| metadata.relative_path == viewer.relative_path) {
+                            Some(metadata) => {
+                                egui::Grid::new("file_viewer_metadata_grid").num_columns(2).show(ui, |ui| {
+                                    for (label, value) in metadata_rows(metadata) {
+                                        ui.label(label);
+                                        ui.label(value);
+                                        ui.end_row();
+                                    }
+                                });
+                            }
+                            None => {
+                                ui.label("Loading metadata...");
+                            }
+                        }
+                    });
+                egui::CentralPanel::default().show_inside(ui, |ui| draw_preview(ui, viewer));
+            });
+
+        if download
+            && let (Some(cli_tx), Some(tree), Some(viewer)) = (&self.cli_tx, &self.ui.tree, &self.ui.viewer) {
+            cli_tx.send(Commands::DownloadFile(tree.name.clone(), viewer.relative_path.clone(), destination)).unwrap();
+        }
+        if !open {
+            self.ui.viewer = None;
+            self.ui.file_metadata = None;
+        } else if let Some(relative_path) = self.neighbouring_file(step).filter(|_| step != 0) {
+            self.open_viewer(relative_path);
+        }
+    }
+}
@@ -1,9 +1,11 @@
 pub mod connect_menu;
 pub mod repository_menu;
 pub mod app_utils;
+pub mod file_viewer;
+pub mod repo_stats;
 
 use std::{sync::mpsc, path::PathBuf, io::Write};
-pub use app_utils::{ConnectionStatus, RepoConfig, Commands, ClientConfig, UiState, FileSystemEntry};
+pub use app_utils::{ConnectionStatus, RepoConfig, Commands, ClientConfig, UiState, FileSystemEntry, Thumbnail, Viewer, THUMBNAIL_SIZE, PREVIEW_SIZE};
 
 pub struct App {
     pub config: ClientConfig,
@@ -17,8 +19,21 @@ pub struct App {
     pub ui: UiState,
 }
 
+// decodes a thumbnail from the server into a texture the explorer can draw
+fn load_thumbnail(ctx: &egui::Context, name: &str, thumbnail: Option<Vec<u8>>) -> Thumbnail {
+    match thumbnail.and_then(|thumbnail| image::load_from_memory(&thumbnail).ok()) {
+        Some(image) => {
+            let rgba = image.to_rgba8();
+            let size = [rgba.width() as usize, rgba.height() as usize];
+            let color_image = egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_raw());
+            Thumbnail::Loaded(ctx.load_texture(name, color_image, egui::TextureOptions::LINEAR))
+        }
+        None => Thumbnail::Unavailable,
+    }
+}
+
 impl App {
-    fn client_command_receiver(&mut self) {
+    fn client_command_receiver(&mut self, ctx: &egui::Context) {
         while let Ok(msg) = self.app_rx.try_recv() {
             match msg {
                 Commands::Notify(msg) => {
@@ -29,30 +44,25 @@ impl App {
                     writeln!(self.log_file, "{}", msg).ok();
                 }
 
-                Commands::GetSubDir(subdir_name) => {
+                Commands::GetSubDir(directory) => {
                     self.ui.subdir_contents = None;
                     if let Some(tree) = &self.ui.tree {
-                        if let Some(contents) = tree.content.get(&subdir_name) {
-                            let mut fs_entries:Vec<FileSystemEntry> = Vec::new();
-                            for entry in contents {
-                                let fs_entry = FileSystemEntry {
-                                    name: entry.clone(),
-                                    is_directory: tree.content.contains_key(entry),
-                                };
-                                fs_entries.push(fs_entry);
-                            }
+                        let fs_entries:Vec<FileSystemEntry> = tree.list_directory(&directory)
+                            .into_iter()
+                            .map(|(name, is_directory)| FileSystemEntry { name, is_directory })
+                            .collect();
+                        if !fs_entries.is_empty() {
                             self.ui.subdir_contents = Some(fs_entries);
                         }
                     }
                 }
                 Commands::PostRepoTree(tree, repo_name) => {
-                    self.ui.tree = Some(tree);
-                    if let Some(repo_config) = self.config.repo_config.get(&repo_name) {
-                        let watch_directory = &repo_config.watch_directory;
-                        if let Some(root) = watch_directory.split('/').last() {
-                            self.app_tx.send(Commands::GetSubDir(root.to_string())).ok(); // maybe issue well see
-                        }
+                    // a new tree usually means new uploads, so the numbers shown for it are stale
+                    if self.ui.repo_stats.as_ref().is_some_and(|stats| stats.repo_name == repo_name) {
+                        self.request_repo_stats(&repo_name);
                     }
+                    self.ui.tree = Some(tree);
+                    self.app_tx.send(Commands::GetSubDir(self.ui.file_explorer_path.join("/"))).ok();
                 }
                 
                 Commands::PostRepos(repos) => {       
@@ -69,6 +79,37 @@ impl App {
 
                 Commands::UpdateConnectionStatus(status) => self.ui.connection_status = status,
 
+                Commands::PostFileMetadata(metadata) => self.ui.file_metadata = Some(metadata),
+
+                Commands::PostSearchResults(results) => self.ui.search_results = Some(results),
+
+                Commands::PostRepoStats(stats) => self.ui.repo_stats = Some(stats),
+
+                Commands::PostPendingDevices(devices) => self.ui.pending_devices = devices,
+
+                Commands::PostThumbnail(hash, size, thumbnail) => {
+                    if size == PREVIEW_SIZE {
+                        // the viewer may have moved on to another file by the time the preview arrives
+                        if let Some(viewer) = self.ui.viewer.as_mut().filter(|viewer| viewer.hash == hash) {
+                            viewer.preview = load_thumbnail(ctx, &format!("{}-{}", hash, size), thumbnail);
+                        }
+                    } else {
+                        let thumbnail = load_thumbnail(ctx, &format!("{}-{}", hash, size), thumbnail);
+                        self.ui.thumbnails.insert(hash, thumbnail);
+                    }
+                }
+
+                Commands::PinCertificate(server_address, fingerprint) => {
+                    self.config.pinned_certificates.insert(server_address, fingerprint);
+                    self.config.save_to_file(self.config_path.to_str().unwrap());
+                }
+
+                Commands::PinServerKey(server_address, public_key) => {
+                    self.config.pinned_server_keys.insert(server_address, public_key);
+                    self.config.enrollment_code.clear();
+                    self.config.save_to_file(self.config_path.to_str().unwrap());
+                }
+
                 Commands::RemoveRepository(repo) => {
                     if let Some(tree) = self.ui.tree.take() {
                         let tree_path = tree.path.clone();
@@ -95,8 +136,9 @@ impl eframe::App for App {
             self.connect_menu(ui);       
             self.repository_menu(ui);
         });
+        self.file_viewer(ctx);
         
-        self.client_command_receiver();
+        self.client_command_receiver(ctx);
         ctx.request_repaint();
     }
 }
\ No newline at end of file
@@ -0,0 +1,208 @@
+use egui::{pos2, vec2, CollapsingHeader, Color32, ProgressBar, Rect, Sense};
+use shared::{RepoQuota, RepoQuotaUpdate, RepoStats, StatsBucket};
+use super::{App, Commands};
+use super::file_viewer::format_time;
+
+// how many extensions or devices get their own bar, the rest are added up into one
+const MAX_BARS: usize = 6;
+const BAR_WIDTH: f32 = 160.0;
+const BAR_HEIGHT: f32 = 14.0;
+const MONTH_CHART_HEIGHT: f32 = 80.0;
+const MAX_COLUMN_WIDTH: f32 = 16.0;
+
+// sizes in the largest unit that keeps them at or above 1
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
+// the largest buckets followed by everything else added up, the server sends them most files first
+fn top_buckets(buckets: &[StatsBucket], empty_label: &str) -> Vec<StatsBucket> {
+    let split = buckets.len().min(MAX_BARS);
+    let mut top: Vec<StatsBucket> = buckets[..split].iter()
+        .map(|bucket| StatsBucket {
+            label: if bucket.label.is_empty() { empty_label.to_string() } else { bucket.label.clone() },
+            ..bucket.clone()
+        })
+        .collect();
+    let rest = &buckets[split..];
+    if !rest.is_empty() {
+        top.push(StatsBucket {
+            label: format!("{} others", rest.len()),
+            file_count: rest.iter().map(|bucket| bucket.file_count).sum(),
+            bytes: rest.iter().map(|bucket| bucket.bytes).sum(),
+        });
+    }
+    top
+}
+
+// every month from the first to the last, the server leaves out months nothing was taken in
+fn fill_months(months: &[StatsBucket]) -> Vec<StatsBucket> {
+    let parse = |label: &str| -> Option<(i32, u32)> {
+        let (year, month) = label.split_once('-')?;
+        Some((year.parse().ok()?, month.parse().ok()?))
+    };
+    let dated: Vec<((i32, u32), &StatsBucket)> = months.iter()
+        .filter_map(|bucket| parse(&bucket.label).map(|month| (month, bucket)))
+        .collect();
+    let (Some((first, _)), Some((last, _))) = (dated.first(), dated.last()) else {
+        return Vec::new();
+    };
+
+    let mut filled = Vec::new();
+    let mut remaining = dated.iter().peekable();
+    let (mut year, mut month) = *first;
+    while (year, month) <= *last {
+        match remaining.next_if(|(dated_month, _)| *dated_month == (year, month)) {
+            Some((_, bucket)) => filled.push((*bucket).clone()),
+            None => filled.push(StatsBucket { label: format!("{:04}-{:02}", year, month), ..Default::default() }),
+        }
+        (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    }
+    filled
+}
+
+fn bar_chart(ui: &mut egui::Ui, id_salt: &str, buckets: &[StatsBucket]) {
+    let most = buckets.iter().map(|bucket| bucket.file_count).max().unwrap_or(0).max(1);
+    egui::Grid::new(id_salt).num_columns(3).show(ui, |ui| {
+        for bucket in buckets {
+            ui.label(&bucket.label);
+            let (rect, response) = ui.allocate_exact_size(vec2(BAR_WIDTH, BAR_HEIGHT), Sense::hover());
+            let width = rect.width() * bucket.file_count as f32 / most as f32;
+            ui.painter().rect_filled(Rect::from_min_size(rect.min, vec2(width, rect.height())), 2.0, ui.visuals().selection.bg_fill);
+            response.on_hover_text(format_bytes(bucket.bytes));
+            ui.label(bucket.file_count.to_string());
+            ui.end_row();
+        }
+    });
+}
+
+// one column per month, hovering a column shows its numbers
+fn month_chart(ui: &mut egui::Ui, months: &[StatsBucket]) {
+    let (Some(first), Some(last)) = (months.first(), months.last()) else {
+        ui.label("Nothing has a capture date");
+        return;
+    };
+    let most = months.iter().map(|month| month.file_count).max().unwrap_or(0).max(1);
+    let column_width = (ui.available_width() / months.len() as f32).min(MAX_COLUMN_WIDTH);
+    let (rect, response) = ui.allocate_exact_size(vec2(column_width * months.len() as f32, MONTH_CHART_HEIGHT), Sense::hover());
+    let hovered = response.hover_pos().map(|pos| ((pos.x - rect.left()) / column_width) as usize);
+
+    let painter = ui.painter_at(rect);
+    for (i, month) in months.iter().enumerate() {
+        let left = rect.left() + i as f32 * column_width;
+        let height = rect.height() * month.file_count as f32 / most as f32;
+        let column = Rect::from_min_max(pos2(left, rect.bottom() - height), pos2(left + (column_width - 1.0).max(1.0), rect.bottom()));
+        let color = if hovered == Some(i) { ui.visuals().selection.stroke.color } else { ui.visuals().selection.bg_fill };
+        painter.rect_filled(column, 1.0, color);
+    }
+    if let Some(month) = hovered.and_then(|i| months.get(i)) {
+        response.on_hover_text(format!("{}: {} files, {}", month.label, month.file_count, format_bytes(month.bytes)));
+    }
+    ui.horizontal(|ui| {
+        ui.small(&first.label);
+        ui.small("to");
+        ui.small(&last.label);
+    });
+}
+
+// a bar that turns orange as the repository nears a limit and red once it reaches it
+fn usage_bar(ui: &mut egui::Ui, used: u64, limit: u64, text: String) {
+    let fraction = used as f32 / limit.max(1) as f32;
+    let mut bar = ProgressBar::new(fraction.min(1.0)).desired_width(BAR_WIDTH).text(text);
+    if fraction >= 1.0 {
+        bar = bar.fill(Color32::RED);
+    } else if fraction >= 0.9 {
+        bar = bar.fill(Color32::ORANGE);
+    }
+    ui.add(bar);
+}
+
+fn quota_usage(ui: &mut egui::Ui, stats: &RepoStats) {
+    if stats.quota == RepoQuota::default() {
+        ui.label("No quota");
+        return;
+    }
+    if let Some(max_files) = stats.quota.max_files {
+        usage_bar(ui, stats.file_count, max_files, format!("{} of {} files", stats.file_count, max_files));
+    }
+    if let Some(max_bytes) = stats.quota.max_bytes {
+        usage_bar(ui, stats.total_bytes, max_bytes, format!("{} of {}", format_bytes(stats.total_bytes), format_bytes(max_bytes)));
+    }
+}
+
+impl App {
+    pub fn request_repo_stats(&self, repo_name: &str) {
+        if let Some(cli_tx) = &self.cli_tx {
+            cli_tx.send(Commands::GetRepoStats(repo_name.to_string())).unwrap();
+        }
+    }
+
+    pub fn repo_stats_panel(&mut self, ui: &mut egui::Ui, repo_name: &str) {
+        ui.horizontal(|ui| {
+            ui.strong("Statistics");
+            if ui.small_button("Refresh").clicked() {
+                self.request_repo_stats(repo_name);
+            }
+        });
+        let Some(stats) = self.ui.repo_stats.as_ref().filter(|stats| stats.repo_name == repo_name) else {
+            ui.label("No statistics yet");
+            return;
+        };
+        stats_summary(ui, stats);
+        quota_usage(ui, stats);
+
+        // only server admins can change quotas, the server says so if anyone else tries
+        let mut update = None;
+        CollapsingHeader::new("Change quota").show(ui, |ui| {
+            let form = &mut self.ui.quota;
+            egui::Grid::new("quota_form").num_columns(2).show(ui, |ui| {
+                ui.label("Max files");
+                ui.add(egui::TextEdit::singleline(&mut form.max_files).hint_text("unlimited"));
+                ui.end_row();
+                ui.label("Max size (MB)");
+                ui.add(egui::TextEdit::singleline(&mut form.max_size).hint_text("unlimited"));
+                ui.end_row();
+            });
+            if ui.button("Set quota").clicked() {
+                update = Some(form.quota());
+            }
+        });
+        match update {
+            Some(Ok(quota)) => {
+                if let Some(cli_tx) = &self.cli_tx {
+                    cli_tx.send(Commands::SetRepoQuota(RepoQuotaUpdate { repo_name: repo_name.to_string(), quota })).unwrap();
+                }
+                self.ui.quota = Default::default();
+            }
+            Some(Err(e)) => self.ui.notification = Some(e),
+            None => {}
+        }
+    }
+}
+
+fn stats_summary(ui: &mut egui::Ui, stats: &RepoStats) {
+    ui.label(format!("{} files, {}", stats.file_count, format_bytes(stats.total_bytes)));
+    if let Some(last_upload) = stats.last_upload {
+        ui.label(format!("Last upload {}", format_time(last_upload)));
+    }
+    if stats.file_count == 0 {
+        return;
+    }
+
+    CollapsingHeader::new("By extension").default_open(true).show(ui, |ui| {
+        bar_chart(ui, "stats_by_extension", &top_buckets(&stats.by_extension, "none"));
+    });
+    CollapsingHeader::new("By month taken").default_open(true).show(ui, |ui| {
+        month_chart(ui, &fill_months(&stats.by_month));
+    });
+    CollapsingHeader::new("Devices").show(ui, |ui| {
+        bar_chart(ui, "stats_by_device", &top_buckets(&stats.devices, "unknown"));
+    });
+}
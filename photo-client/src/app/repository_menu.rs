@@ -1,6 +1,21 @@
 use super::App;
 use egui::{Checkbox, Color32, Frame, RichText, ScrollArea};
-use super::{Commands, ConnectionStatus};
+use shared::{media_type, RepoAccess, Role, SearchQuery};
+use super::{Commands, ConnectionStatus, Thumbnail, THUMBNAIL_SIZE};
+use super::file_viewer::format_time;
+use super::repo_stats::format_bytes;
+
+// how big each cell of the thumbnail grid is drawn, the thumbnails themselves are fetched larger for high dpi screens
+const GRID_CELL_SIZE: f32 = 128.0;
+
+// the tree path of an entry in the directory the explorer is showing
+pub fn entry_path(directory: &[String], name: &str) -> String {
+    directory.iter()
+        .map(String::as_str)
+        .chain([name])
+        .collect::<Vec<&str>>()
+        .join("/")
+}
 
 impl App {
     fn repository_list(&mut self, ui: &mut egui::Ui) {
@@ -15,17 +30,16 @@ impl App {
                     self.ui.selected_repo = Some(i);
                     let repo_name = repo.to_string();
                     self.ui.file_explorer_path.clear();
-                    
-                    if let Some(repo_config) = self.config.repo_config.get(&repo_name) {
-                        let watch_directory = &repo_config.watch_directory;
-                        if let Some(root) = watch_directory.split('/').last() {
-                            self.ui.file_explorer_path.push(root.to_string());
-                        }
-                    }
+                    self.ui.file_metadata = None;
+                    self.ui.viewer = None;
+                    self.ui.search_query = None;
+                    self.ui.search_results = None;
+                    self.ui.repo_stats = None;
                     
                     if let Some(cli_tx) = &self.cli_tx {
                         cli_tx.send(Commands::GetRepoTree(repo_name.clone())).unwrap();
                     }
+                    self.request_repo_stats(&repo_name);
                 }
             }
             if ui.button("New Repository").clicked() {
@@ -128,10 +142,45 @@ impl App {
                             self.ui.file_explorer_path.clear();
                             self.ui.subdir_contents = None;
                             self.ui.tree = None;
+                            self.ui.viewer = None;
+                            self.ui.repo_stats = None;
                             self.ui.selected_repo = None;
                         }
                     }
                 }
+                ui.horizontal(|ui| {
+                    ui.label("Share with:");
+                    ui.text_edit_singleline(&mut self.ui.share_user_name);
+                    egui::ComboBox::from_id_salt("share_role")
+                        .selected_text(format!("{}", self.ui.share_role))
+                        .show_ui(ui, |ui| {
+                            for role in [Role::Reader, Role::Writer, Role::Owner] {
+                                ui.selectable_value(&mut self.ui.share_role, role, format!("{}", role));
+                            }
+                        });
+
+                    if !self.ui.share_user_name.is_empty() {
+                        // the server only lets owners change who has access
+                        let grant = ui.button("Grant").clicked();
+                        let revoke = ui.button("Revoke").clicked();
+                        if grant || revoke {
+                            if let Some(cli_tx) = &self.cli_tx {
+                                let access = RepoAccess {
+                                    repo_name: repo_name.clone(),
+                                    user_name: self.ui.share_user_name.trim().to_string(),
+                                    role: if grant { Some(self.ui.share_role) } else { None },
+                                };
+                                cli_tx.send(Commands::SetRepoAccess(access)).unwrap();
+                            }
+                            self.ui.share_user_name.clear();
+                        }
+                    }
+                });
+
+                ui.separator();
+                self.repo_stats_panel(ui, &repo_name);
+                ui.separator();
+
                 if ui.button("Remove repository").clicked() {
                     self.ui.show_remove_ui = !self.ui.show_remove_ui;
                     if !self.ui.show_remove_ui {
@@ -171,26 +220,231 @@ impl App {
         });
     }
 
+    fn send_search(&mut self, query: SearchQuery) {
+        if let Some(cli_tx) = &self.cli_tx {
+            cli_tx.send(Commands::Search(query.clone())).unwrap();
+        }
+        self.ui.search_query = Some(query);
+    }
+
+    fn search_box(&mut self, ui: &mut egui::Ui) {
+        let Some(repo_name) = self.ui.tree.as_ref().map(|tree| tree.name.clone()) else {
+            return;
+        };
+        let form = &mut self.ui.search;
+
+        let mut search = false;
+        ui.horizontal(|ui| {
+            let response = ui.add(egui::TextEdit::singleline(&mut form.name).hint_text("Search file names, eg. IMG_* or holiday"));
+            search = response.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter));
+            search |= ui.button("Search").clicked();
+            ui.toggle_value(&mut form.show_filters, "Filters");
+        });
+        if form.show_filters {
+            egui::Grid::new("search_filters").num_columns(4).show(ui, |ui| {
+                ui.label("Extension");
+                ui.text_edit_singleline(&mut form.extension);
+                ui.label("Camera");
+                ui.text_edit_singleline(&mut form.camera);
+                ui.end_row();
+                ui.label("Min size (MB)");
+                ui.text_edit_singleline(&mut form.min_size);
+                ui.label("Max size (MB)");
+                ui.text_edit_singleline(&mut form.max_size);
+                ui.end_row();
+                ui.label("Taken from");
+                ui.add(egui::TextEdit::singleline(&mut form.captured_from).hint_text("YYYY-MM-DD"));
+                ui.label("Taken until");
+                ui.add(egui::TextEdit::singleline(&mut form.captured_to).hint_text("YYYY-MM-DD"));
+                ui.end_row();
+            });
+        }
+
+        if search {
+            match form.query(&repo_name) {
+                Ok(query) => self.send_search(query),
+                Err(e) => self.ui.notification = Some(e),
+            }
+        }
+    }
+
+    // shown in place of the directory listing until the search is cleared
+    fn search_results(&mut self, ui: &mut egui::Ui) {
+        let Some(results) = &self.ui.search_results else {
+            return;
+        };
+
+        let mut clear = false;
+        let mut page = None;
+        let mut picked_file = None;
+        let page_count = results.total.div_ceil(results.page_size.max(1) as u64).max(1);
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{} files found", results.total));
+            if ui.button("Clear search").clicked() {
+                clear = true;
+            }
+            if results.page > 0 && ui.button("◀").clicked() {
+                page = Some(results.page - 1);
+            }
+            ui.label(format!("page {} of {}", results.page + 1, page_count));
+            if ((results.page + 1) as u64) < page_count && ui.button("▶").clicked() {
+                page = Some(results.page + 1);
+            }
+        });
+
+        ScrollArea::vertical()
+        .auto_shrink([false;2])
+        .show(ui, |ui| {
+            egui::Grid::new("search_results").num_columns(3).striped(true).show(ui, |ui| {
+                for file in &results.files {
+                    let selected = self.ui.viewer.as_ref().is_some_and(|viewer| viewer.relative_path == file.relative_path);
+                    if ui.selectable_label(selected, &file.relative_path).clicked() {
+                        picked_file = Some(file.relative_path.clone());
+                    }
+                    ui.label(format!("{} bytes", file.size));
+                    ui.label(format_time(file.media.captured.unwrap_or(file.created)));
+                    ui.end_row();
+                }
+            });
+        });
+
+        if clear {
+            self.ui.search_results = None;
+            self.ui.search_query = None;
+        }
+        if let Some(query) = page.and_then(|page| self.ui.search_query.clone().map(|query| SearchQuery { page, ..query })) {
+            self.send_search(query);
+        }
+        if let Some(relative_path) = picked_file {
+            // browse to the file's folder so the viewer can step through its neighbours
+            self.ui.file_explorer_path = relative_path.split('/').map(str::to_string).collect();
+            self.ui.file_explorer_path.pop();
+            self.app_tx.send(Commands::GetSubDir(self.ui.file_explorer_path.join("/"))).unwrap();
+            self.open_viewer(relative_path);
+        }
+    }
+
+    // only the rows on screen are drawn, and only their thumbnails are fetched
+    fn thumbnail_grid(&mut self, ui: &mut egui::Ui) {
+        let (Some(contents), Some(tree)) = (&self.ui.subdir_contents, &self.ui.tree) else {
+            return;
+        };
+
+        let item_spacing = ui.spacing().item_spacing;
+        let columns = ((ui.available_width() + item_spacing.x) / (GRID_CELL_SIZE + item_spacing.x)).floor().max(1.0) as usize;
+        let row_height = GRID_CELL_SIZE + item_spacing.y + ui.text_style_height(&egui::TextStyle::Body);
+        let rows = contents.len().div_ceil(columns);
+
+        let mut opened_directory = None;
+        let mut picked_file = None;
+        let mut wanted_thumbnails = Vec::<(String, String)>::new();
+
+        ScrollArea::vertical()
+        .auto_shrink([false;2])
+        .show_rows(ui, row_height, rows, |ui, row_range| {
+            for row in row_range {
+                ui.horizontal(|ui| {
+                    for entry in contents.iter().skip(row * columns).take(columns) {
+                        let relative_path = entry_path(&self.ui.file_explorer_path, &entry.name);
+                        let hash = tree.files.get(&relative_path).map(|file| file.hash.clone());
+
+                        ui.allocate_ui(egui::vec2(GRID_CELL_SIZE, row_height), |ui| {
+                            ui.vertical_centered(|ui| {
+                                let cell = [GRID_CELL_SIZE, GRID_CELL_SIZE];
+                                let thumbnail = hash.as_ref().and_then(|hash| self.ui.thumbnails.get(hash));
+                                let response = match thumbnail {
+                                    _ if entry.is_directory => ui.add_sized(cell, egui::Button::new(RichText::new("📁").size(48.0))),
+                                    Some(Thumbnail::Loaded(texture)) => ui.add_sized(cell, egui::Image::new(texture)
+                                        .max_size(egui::vec2(GRID_CELL_SIZE, GRID_CELL_SIZE))
+                                        .sense(egui::Sense::click())),
+                                    Some(Thumbnail::Requested) => ui.add_sized(cell, egui::Spinner::new()),
+                                    _ => ui.add_sized(cell, egui::Button::new(RichText::new("📄").size(48.0))),
+                                };
+                                ui.add(egui::Label::new(entry.name.clone()).truncate());
+
+                                if response.clicked() {
+                                    if entry.is_directory {
+                                        opened_directory = Some(entry.name.clone());
+                                    } else {
+                                        picked_file = Some(relative_path.clone());
+                                    }
+                                }
+                            });
+                        });
+
+                        // images get their thumbnail fetched the first time they scroll into view, everything else keeps its icon
+                        if let Some(hash) = hash.filter(|hash| media_type(&relative_path) == "image" && !self.ui.thumbnails.contains_key(hash)) {
+                            wanted_thumbnails.push((relative_path, hash));
+                        }
+                    }
+                });
+            }
+        });
+
+        let repo_name = tree.name.clone();
+        for (relative_path, hash) in wanted_thumbnails {
+            if let Some(cli_tx) = &self.cli_tx {
+                self.ui.thumbnails.insert(hash.clone(), Thumbnail::Requested);
+                cli_tx.send(Commands::GetThumbnail(repo_name.clone(), relative_path, hash, THUMBNAIL_SIZE)).unwrap();
+            }
+        }
+        if let Some(relative_path) = picked_file {
+            self.open_viewer(relative_path);
+        }
+        if let Some(directory) = opened_directory {
+            self.ui.file_explorer_path.push(directory);
+            self.app_tx.send(Commands::GetSubDir(self.ui.file_explorer_path.join("/"))).unwrap();
+        }
+    }
+
     fn file_explorer(&mut self, ui: &mut egui::Ui) {
       if self.ui.connection_status == ConnectionStatus::Connected {
         ui.vertical(|ui| {
+            self.search_box(ui);
             ui.horizontal(|ui| {
                 ui.heading("File Explorer");
                 if let Some(tree) = &self.ui.tree {
-                    ui.label(format!("{} files, {} subdirs", tree.history.len(), tree.content.len()));
+                    let total_size = self.ui.repo_stats.as_ref()
+                        .filter(|stats| stats.repo_name == tree.name)
+                        .map(|stats| format!(", {}", format_bytes(stats.total_bytes)))
+                        .unwrap_or_default();
+                    ui.label(format!("{} files, {} subdirs{}", tree.files.len(), tree.directories().len(), total_size));
                 }
+                ui.checkbox(&mut self.ui.thumbnail_grid, "Thumbnails");
             });
             ui.horizontal(|ui| {
-                for i in 0..self.ui.file_explorer_path.len() {
-                    let subdir = self.ui.file_explorer_path[i].clone();
-                    if ui.button(format!("/{}", subdir)).clicked() {
-                        self.ui.file_explorer_path.truncate(i+1);
-                        self.app_tx.send(Commands::GetSubDir(subdir.clone())).unwrap();
+                // the first button goes back to the repository root, the rest to each directory on the way down
+                for i in 0..=self.ui.file_explorer_path.len() {
+                    let label = match i {
+                        0 => "/".to_string(),
+                        _ => format!("{}/", self.ui.file_explorer_path[i-1]),
+                    };
+                    if ui.button(label).clicked() {
+                        self.ui.file_explorer_path.truncate(i);
+                        self.app_tx.send(Commands::GetSubDir(self.ui.file_explorer_path.join("/"))).unwrap();
                         break;
                     };
                 }
             });
-            if let Some(contents) = &self.ui.subdir_contents {
+            ui.horizontal(|ui| {
+                ui.label("Restore to:");
+                ui.text_edit_singleline(&mut self.ui.restore_destination);
+                if let Some(tree) = &self.ui.tree {
+                    let directory = self.ui.file_explorer_path.join("/");
+                    if !self.ui.restore_destination.is_empty() && ui.button(format!("Restore /{}", directory)).clicked() {
+                        if let Some(cli_tx) = &self.cli_tx {
+                            cli_tx.send(Commands::RestoreRepository(tree.name.clone(), directory, self.ui.restore_destination.clone())).unwrap();
+                        }
+                    }
+                }
+            });
+            let mut picked_file = None;
+            if self.ui.search_results.is_some() {
+                self.search_results(ui);
+            } else if self.ui.thumbnail_grid && self.ui.subdir_contents.is_some() {
+                self.thumbnail_grid(ui);
+            } else if let Some(contents) = &self.ui.subdir_contents {
                 ScrollArea::vertical()
                 .auto_shrink([false;2])
                 .show(ui, |ui| {
@@ -198,16 +452,23 @@ impl App {
                         if entry.is_directory{
                             if ui.button(entry.name.to_string()).clicked() {
                                 self.ui.file_explorer_path.push(entry.name.clone());
-                                self.app_tx.send(Commands::GetSubDir(entry.name.clone())).unwrap();
+                                self.app_tx.send(Commands::GetSubDir(self.ui.file_explorer_path.join("/"))).unwrap();
                             }
                         } else {
-                            ui.label(entry.name.clone());
+                            let relative_path = entry_path(&self.ui.file_explorer_path, &entry.name);
+                            let selected = self.ui.viewer.as_ref().is_some_and(|viewer| viewer.relative_path == relative_path);
+                            if ui.selectable_label(selected, entry.name.clone()).clicked() {
+                                picked_file = Some(relative_path);
+                            }
                         }
                     }
                 });
             } else {
                 ui.label("Directory is Empty");
             }
+            if let Some(relative_path) = picked_file {
+                self.open_viewer(relative_path);
+            }
           });
       }  
     }
@@ -0,0 +1,220 @@
+use super::Client;
+use std::path::{Path, PathBuf};
+use shared::{tree::sanitize_relative_path, FileMetadata, Log, Notify, Request, RequestTypes, ResponseCodes, ThumbnailRequest, read_job, read_response, send_request};
+use crate::app::Commands;
+use serde_json::json;
+
+const THUMBNAIL_DIRECTORY: &str = "photo-client-thumbnails";
+
+// thumbnails are cached by content hash, so renamed and duplicate files only get fetched once
+fn cached_thumbnail_path(hash:&str, size:u32) -> Option<PathBuf> {
+    if hash.len() < 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(Path::new(THUMBNAIL_DIRECTORY).join(&hash[..2]).join(format!("{}-{}.jpg", hash, size)))
+}
+
+fn write_local_file(local_path:&Path, data:&[u8]) -> std::io::Result<()> {
+    if let Some(parent) = local_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(local_path, data)
+}
+
+impl Client {
+
+    // the part of a tree path that sits below directory, eg. DCIM/2024/a.jpg -> 2024/a.jpg for DCIM
+    // paths come from the server, so they are sanitized before they are joined onto a local directory
+    fn relative_to_directory(relative_path:&str, directory:&str) -> Option<PathBuf> {
+        let below = relative_path.strip_prefix(directory)
+            .map(|rest| rest.trim_start_matches('/'))
+            .unwrap_or(relative_path);
+        Some(sanitize_relative_path(below)?.split('/').collect())
+    }
+
+    // problems on this machine are reported rather than returned, returning would drop the connection
+    fn report_local_error(&self, message:String) -> anyhow::Result<()> {
+        self.app_tx.send(Commands::Log(message.clone()))?;
+        self.app_tx.send(Commands::Notify(message))?;
+        Ok(())
+    }
+
+    // saves one file into destination under its own name
+    pub fn download_file(&mut self, repo_name:String, relative_path:String, destination:String) -> anyhow::Result<()> {
+        let destination = PathBuf::from(destination);
+        if destination.to_string_lossy().is_empty() {
+            return self.report_local_error("no download destination was given".to_string());
+        }
+
+        let body = json!({
+            "repo_name": repo_name,
+            "path": relative_path,
+        });
+
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::DownloadFile,
+                body: serde_json::to_vec(&body)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                let job = read_job(stream)?;
+                self.log_response(&response)?;
+                if let Err(e) = job.verify() {
+                    self.app_tx.send(Commands::Log(format!("{} | [ {} ]", ResponseCodes::IntegrityError, e)))?;
+                    self.app_tx.send(Commands::Notify(e))?;
+                    return Ok(());
+                }
+                let Some(file_name) = sanitize_relative_path(&relative_path).and_then(|path| path.rsplit('/').next().map(str::to_string)) else {
+                    return self.report_local_error(format!("{} doesn't name a file", relative_path));
+                };
+                let local_path = destination.join(file_name);
+                if let Err(e) = write_local_file(&local_path, &job.data) {
+                    return self.report_local_error(format!("{} could not be written | {}", local_path.to_string_lossy(), e));
+                }
+
+                let message = format!("downloaded {}", local_path.to_string_lossy());
+                self.app_tx.send(Commands::Log(message.clone()))?;
+                self.app_tx.send(Commands::Notify(message))?;
+                return Ok(());
+            }
+            self.log_response(&response)?;
+            self.notify_app(&response)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_file_metadata(&mut self, repo_name:String, relative_path:String) -> anyhow::Result<()> {
+        let body = json!({
+            "repo_name": repo_name,
+            "path": relative_path,
+        });
+
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::GetFileMetadata,
+                body: serde_json::to_vec(&body)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                let metadata: FileMetadata = serde_json::from_slice(&response.body)?;
+                self.app_tx.send(Commands::PostFileMetadata(metadata))?;
+            } else {
+                self.log_response(&response)?;
+                self.notify_app(&response)?;
+            }
+        }
+        Ok(())
+    }
+
+    // answers the app with the thumbnail from the disk cache or the server, or None so it can show an icon instead
+    pub fn get_thumbnail(&mut self, repo_name:String, relative_path:String, hash:String, size:u32) -> anyhow::Result<()> {
+        let cached_path = cached_thumbnail_path(&hash, size);
+        if let Some(thumbnail) = cached_path.as_ref().and_then(|path| std::fs::read(path).ok()) {
+            self.app_tx.send(Commands::PostThumbnail(hash, size, Some(thumbnail)))?;
+            return Ok(());
+        }
+
+        let thumbnail_request = ThumbnailRequest {
+            repo_name,
+            path: relative_path,
+            size,
+        };
+
+        let mut thumbnail = None;
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::GetThumbnail,
+                body: serde_json::to_vec(&thumbnail_request)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                if let Some(cached_path) = &cached_path {
+                    // a cache that can't be written only means fetching the thumbnail again next time
+                    let written = cached_path.parent()
+                        .map_or(Ok(()), std::fs::create_dir_all)
+                        .and_then(|_| std::fs::write(cached_path, &response.body));
+                    if let Err(e) = written {
+                        self.app_tx.send(Commands::Log(format!("Unable to cache the thumbnail of {} | {}", thumbnail_request.path, e)))?;
+                    }
+                }
+                thumbnail = Some(response.body);
+            } else {
+                self.log_response(&response)?;
+            }
+        }
+        self.app_tx.send(Commands::PostThumbnail(hash, size, thumbnail))?;
+        Ok(())
+    }
+
+    pub fn restore_repository(&mut self, repo_name:String, directory:String, destination:String) -> anyhow::Result<()> {
+        let destination = PathBuf::from(destination);
+        if destination.to_string_lossy().is_empty() {
+            return self.report_local_error("no restore destination was given".to_string());
+        }
+
+        let body = json!({
+            "repo_name": repo_name,
+            "directory": directory,
+        });
+
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::RestoreRepository,
+                body: serde_json::to_vec(&body)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                let manifest: Vec<String> = serde_json::from_slice(&response.body)?;
+                self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+
+                // the server streams the files in manifest order, each behind a response saying whether it could be read
+                for (i, relative_path) in manifest.iter().enumerate() {
+                    let file_response = read_response(stream)?;
+                    if file_response.status_code != ResponseCodes::OK {
+                        self.app_tx.send(Commands::Log(format!("{} | [ {} ]", file_response.status_code, String::from_utf8_lossy(&file_response.body))))?;
+                        continue;
+                    }
+                    let job = read_job(stream)?;
+                    if let Err(e) = job.verify() {
+                        self.app_tx.send(Commands::Log(format!("{} | [ {} ]", ResponseCodes::IntegrityError, e)))?;
+                        continue;
+                    }
+                    let Some(below) = Self::relative_to_directory(relative_path, &directory) else {
+                        self.app_tx.send(Commands::Log(format!("skipped {}, it is not a valid path inside the destination", relative_path)))?;
+                        continue;
+                    };
+                    let local_path = destination.join(below);
+
+                    // the rest of the files are still on the stream, so a file that can't be written is only skipped
+                    if let Err(e) = write_local_file(&local_path, &job.data) {
+                        let message = format!("{} could not be written | {}", local_path.to_string_lossy(), e);
+                        self.app_tx.send(Commands::Log(message.clone()))?;
+                        self.app_tx.send(Commands::Notify(message))?;
+                        continue;
+                    }
+
+                    let message = format!("restored {} ({}/{})", local_path.to_string_lossy(), i + 1, manifest.len());
+                    self.app_tx.send(Commands::Log(message.clone()))?;
+                    self.app_tx.send(Commands::Notify(message))?;
+                }
+            } else {
+                self.log_response(&response)?;
+                self.app_tx.send(Commands::Notify(response.status_message))?;
+            }
+        }
+        Ok(())
+    }
+}
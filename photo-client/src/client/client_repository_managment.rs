@@ -1,12 +1,12 @@
 use super::Client;
-use std::{collections::HashMap, path::PathBuf, sync::{Arc, atomic, mpsc}};
-use shared::{FileHeader, Log, Notify, Request, RequestTypes, ResponseCodes, Tree, Job, BatchJob, read_response, send_request};
-use crate::{app::{Commands, ConnectionStatus, RepoConfig}, filestreamclient::BatchLoaderCallback};
+use std::{path::{Path, PathBuf}, sync::{Arc, atomic, mpsc}};
+use shared::{hash_bytes, upload_id, Codec, FileHeader, Log, Notify, RepoAccess, RepoQuotaUpdate, RepoStats, Request, RequestTypes, ResponseCodes, SearchQuery, SearchResults, Tree, TreeSync, Job, BatchJob, read_response, send_request};
+use crate::{app::{Commands, ConnectionStatus, RepoConfig}, filestreamclient::{relative_path, BatchLoaderCallback}};
 use serde_json::json;
 
 impl Client {
 
-    fn search_subdir(subdir_path:PathBuf, tree: &mut Tree, app_tx:&mpsc::Sender<Commands>, batch_loader_tx:&mpsc::Sender<BatchJob>) -> anyhow::Result<()>{
+    fn search_subdir(watch_directory:&Path, subdir_path:PathBuf, tree: &Tree, app_tx:&mpsc::Sender<Commands>, batch_loader_tx:&mpsc::Sender<BatchJob>) -> anyhow::Result<()>{
         let mut untracked_files = Vec::<String>::new();
         println!("subdir path is {}", subdir_path.to_string_lossy());
         for entry in std::fs::read_dir(&subdir_path)? {
@@ -20,23 +20,11 @@ impl Client {
             };
             app_tx.send(Commands::Log(format!("currently in {}", &name)))?;
             if file_type.is_dir() {
-                if !tree.content.contains_key(name) {
-                    // start tracking this directory
-                    tree.content.insert(name.to_string(), Vec::<String>::new());
-                }
-                Self::search_subdir(path, tree, app_tx, batch_loader_tx)?;
+                Self::search_subdir(watch_directory, path, tree, app_tx, batch_loader_tx)?;
 
             } else if file_type.is_file() {
-                // the tree will never have a file as a key so look in the contents of the tree
-
-                let parent_name = match subdir_path.file_name().and_then(|os| os.to_str()) {
-                    Some(name) => name,
-                    None => continue, // skip file if parent dir is invalid UTF-8
-                };
-
-                let contents = tree.content.get(parent_name).map(|v: &Vec<String>| &v[..]).unwrap_or(&[]);
-
-                if !contents.iter().any(|s| s == name) {
+                // the tree is keyed by the same relative path the server stores the file under
+                if !tree.files.contains_key(&relative_path(watch_directory, &path)) {
                     untracked_files.push(name.to_string());
                 }
             }
@@ -63,18 +51,25 @@ impl Client {
                 .to_string_lossy()
                 .into_owned();
 
+            let data = std::fs::read(&file_path)?;
+            let file_hash = hash_bytes(&data);
+
             let file_header = FileHeader {
                 repo_name: repo_name.to_string(),
                 file_name: file,
                 file_size: file_size as usize,
+                upload_id: upload_id(repo_name, &file_location, &file_hash),
+                relative_path: relative_path(watch_directory, &file_path),
                 file_location,
                 file_ext,
                 file_datetime,
+                file_hash,
+                codec: Codec::None, // the batch loader picks one from what the server agreed to
             };
 
             let job = Job {
               file_header,
-              data: std::fs::read(&file_path)? 
+              data,
             };
             jobs.push(job);
         }
@@ -91,7 +86,7 @@ impl Client {
             None => return Ok(()) // add error
         };
 
-        let mut temp_tree_copy = match self.trees.get(&repo_name) {
+        let tree = match self.trees.get(&repo_name) {
             Some(t) => t.clone(),
             None => return Err(anyhow::anyhow!("unable to locate tree for {}", &repo_name))
         };
@@ -99,7 +94,7 @@ impl Client {
             if watch_directory.to_string_lossy().is_empty() {
                 return Err(anyhow::anyhow!("watch directory is not saved in the config"))
             }
-            Self::search_subdir(watch_directory, &mut temp_tree_copy, &self.app_tx, batch_loader_tx)?;
+            Self::search_subdir(&watch_directory, watch_directory.clone(), &tree, &self.app_tx, batch_loader_tx)?;
             
             if let Some(batch_loader_callback_rx) = &self.batch_loader_callback_rx {
                 let mut stop_flag = false;
@@ -144,13 +139,7 @@ impl Client {
 
             if response.status_code == ResponseCodes::OK {
                 self.get_repositories()?;
-                let tree:Tree = Tree {
-                    version: 0,
-                    content: HashMap::new(),
-                    history: HashMap::new(),
-                    path: ("trees".to_string() + "/" + &repo_name + ".tree").to_string(),
-                    name: repo_name.clone(),
-                };
+                let tree:Tree = Tree::new(&repo_name);
                 self.trees.insert(repo_name.clone(), tree.clone());
                 Tree::save_to_file(&tree,&tree.path);
 
@@ -164,6 +153,87 @@ impl Client {
         Ok(())
     }
 
+    pub fn set_repo_access(&mut self, access:RepoAccess) -> anyhow::Result<()> {
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::SetRepoAccess,
+                body: serde_json::to_vec(&access)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+            self.log_response(&response)?;
+            self.notify_app(&response)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_repo_quota(&mut self, update:RepoQuotaUpdate) -> anyhow::Result<()> {
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::SetRepoQuota,
+                body: serde_json::to_vec(&update)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+            self.log_response(&response)?;
+            self.notify_app(&response)?;
+
+            if response.status_code == ResponseCodes::OK {
+                self.get_repo_stats(update.repo_name)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn search(&mut self, query:SearchQuery) -> anyhow::Result<()> {
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::Search,
+                body: serde_json::to_vec(&query)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            match response.status_code {
+                ResponseCodes::OK | ResponseCodes::Empty => {
+                    let results: SearchResults = serde_json::from_slice(&response.body)?;
+                    self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+                    self.app_tx.send(Commands::PostSearchResults(results))?;
+                }
+                _ => {
+                    self.log_response(&response)?;
+                    self.notify_app(&response)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_repo_stats(&mut self, repo_name:String) -> anyhow::Result<()> {
+        if let Some(stream) = self.command_stream.as_mut() {
+            let request = Request {
+                request_type: RequestTypes::GetRepoStats,
+                body: repo_name.as_bytes().to_vec(),
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                let stats: RepoStats = serde_json::from_slice(&response.body)?;
+                self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+                self.app_tx.send(Commands::PostRepoStats(stats))?;
+            } else {
+                self.log_response(&response)?;
+                self.notify_app(&response)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_repositories(&mut self) -> anyhow::Result<()> { 
         let request = Request {
             request_type: RequestTypes::GetRepos,
@@ -188,7 +258,11 @@ impl Client {
                         self.repo_threads.insert(repo_name.clone(), (file_streaming_client_handle, stop_flag));
                     }
                     let tree_path = ("trees".to_string() + "/" + &repo_name + ".tree").to_string();
-                    let tree:Tree = Tree::load_from_file(&tree_path);
+                    let mut tree:Tree = Tree::load_from_file(&tree_path);
+                    if tree.name.is_empty() {
+                        // a missing or outdated tree file is rebuilt from the server's history
+                        tree = Tree::new(&repo_name);
+                    }
                     self.trees.insert(repo_name, tree);
                 }
             } else {
@@ -231,7 +305,7 @@ impl Client {
             send_request(request, stream)?;
             let response = read_response(stream)?;
             self.log_response(&response)?;
-        
+            self.notify_app(&response)?;
             
             if response.status_code == ResponseCodes::OK {
                 self.config.repo_config.remove(repo_name);
@@ -254,19 +328,52 @@ impl Client {
             tree = existing_tree.clone();
         } else {
             self.app_tx.send(Commands::Log(format!("{} tree not found, initializing tree", repo_name.clone())))?;
-            tree = Tree {
-                    version: 0,
-                    content: HashMap::new(),
-                    history: HashMap::new(),
-                    path: ("trees".to_string() + "/" + &repo_name + ".tree").to_string(),
-                    name: repo_name.clone(),
-                };
+            tree = Tree::new(&repo_name);
             self.trees.insert(repo_name.clone(), tree.clone());
         };
-        
+
+        let mut snapshot = false;
+        loop {
+            let sync = match self.request_tree_sync(&repo_name, tree.version, snapshot)? {
+                Some(sync) => sync,
+                None => return Ok(()),
+            };
+
+            match sync {
+                TreeSync::Updates { head_version, operations } => {
+                    let start_index = tree.version;
+                    if let Err(e) = tree.apply_updates(head_version, operations) {
+                        if snapshot {
+                            return Err(anyhow::anyhow!(e));
+                        }
+                        // the history we were sent doesn't line up with ours, start over from the server's copy
+                        self.app_tx.send(Commands::Log(format!("{}, requesting a snapshot", e)))?;
+                        snapshot = true;
+                        continue;
+                    }
+                    self.app_tx.send(Commands::Log(format!("applied {} history from {} to {}", repo_name, start_index, tree.version)))?;
+                }
+                TreeSync::Snapshot(server_tree) => {
+                    self.app_tx.send(Commands::Log(format!("replaced {} with the server's tree at version {}", repo_name, server_tree.version)))?;
+                    tree = Tree { path: tree.path, ..server_tree };
+                    tree.rebuild();
+                }
+            }
+            break;
+        }
+        tree.compact();
+
+        self.trees.insert(repo_name.clone(), tree.clone());
+        tree.save_to_file(&tree.path);
+        self.app_tx.send(Commands::PostRepoTree(tree, repo_name))?;
+        Ok(())
+    }
+
+    fn request_tree_sync(&mut self, repo_name:&str, version:i32, snapshot:bool) -> anyhow::Result<Option<TreeSync>> {
         let body = json!({
             "repo_name": repo_name,
-            "version": tree.version,
+            "version": version,
+            "snapshot": snapshot,
         });
         if let Some(stream) = self.command_stream.as_mut() {
             let request = Request {
@@ -276,29 +383,15 @@ impl Client {
 
             send_request(request, stream)?;
             let response = read_response(stream)?;
-            self.log_response(&response)?;
-            self.notify_app(&response)?;
 
             if response.status_code == ResponseCodes::OK {
-                if !response.body.is_empty() {
-                    let tree_updates: HashMap<i32, String> =
-                        serde_json::from_slice(&response.body)?;
-
-                    let start_index = tree.version;
-                    for (_, history_entry) in tree_updates {
-                        tree.add_history(history_entry);
-                    }
-                    
-                    println!("applying history from {} to {}", start_index, tree.version);
-                    tree.apply_history(start_index);
-                    self.trees.insert(repo_name.clone(), tree.clone());
-                    tree.save_to_file(&tree.path);
-
-                }
-                
-                self.app_tx.send(Commands::PostRepoTree(tree.clone(), repo_name))?;
+                // the body is the tree itself, too much to put in the log
+                self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+                return Ok(Some(serde_json::from_slice(&response.body)?));
             }
+            self.log_response(&response)?;
+            self.notify_app(&response)?;
         }
-        Ok(())
+        Ok(None)
     }
 }
\ No newline at end of file
@@ -1,16 +1,17 @@
 use std::{sync::mpsc, collections::HashMap, thread::JoinHandle, net::TcpStream, sync::Arc, sync::atomic};
-use shared::{BatchJob, Log, Notify, Request, RequestTypes, Response, ResponseCodes, Tree, read_response, send_request};
+use shared::{auth::{self, Identity}, tls, BatchJob, BatchProcessorOffer, Codec, DataSessionHello, DeviceApproval, PendingDevice, Log, Stream, Notify, Request, RequestTypes, Response, ResponseCodes, StoreStats, Tree, read_response, send_message, send_request};
 use crate::app::{Commands, ClientConfig, ConnectionStatus};
 use crate::filestreamclient::{BatchLoader, BatchLoaderCallback, RepoEventListener};
 
 mod client_repository_managment;
+mod client_file_transfer;
 
 pub struct Client {
     pub app_tx: mpsc::Sender<Commands>,
     app_rx: mpsc::Receiver<Commands>,
     stop_flag: Arc<atomic::AtomicBool>,
     config: ClientConfig,
-    command_stream: Option<TcpStream>,
+    command_stream: Option<Stream>,
     repo_threads: HashMap<String, (std::thread::JoinHandle<()>,Arc<atomic::AtomicBool>)>,
     batch_loader_job_tx: Option<mpsc::Sender<BatchJob>>,
     batch_loader_callback_rx: Option<mpsc::Receiver<BatchLoaderCallback>>,
@@ -40,42 +41,75 @@ impl Client {
                 if std::path::Path::new("photo-client/trees").exists() == false {
                     std::fs::create_dir_all("trees")?;
                 }
+                let s = self.secure_stream(s)?;
                 self.command_stream = Some(s);
+                let server_key = match self.authenticate() {
+                    Ok(server_key) => server_key,
+                    Err(e) => {
+                        self.stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                        self.app_tx.send(Commands::UpdateConnectionStatus(ConnectionStatus::Disconnected))?;
+                        self.app_tx.send(Commands::Notify(format!("Authentication failed. {}", e)))?;
+                        return Err(e);
+                    }
+                };
 
                 // Handle the connection response   
                 if let Some(stream) = &mut self.command_stream {
                     let response = read_response(stream)?;
                     self.log_response(&response)?;
                     self.notify_app(&response)?;
+                    if response.status_code != ResponseCodes::OK {
+                        self.stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                        self.app_tx.send(Commands::UpdateConnectionStatus(ConnectionStatus::Disconnected))?;
+                        return Err(anyhow::anyhow!("{} | [ {} ]", response.status_code, String::from_utf8_lossy(&response.body)));
+                    }
                     self.app_tx.send(Commands::UpdateConnectionStatus(ConnectionStatus::Connected))?;
                 }
 
+                // only pin the server once it has accepted this device, a rejected enrollment can be retried
+                let server_address = self.config.server_address.clone();
+                if !self.config.pinned_server_keys.contains_key(&server_address) {
+                    self.app_tx.send(Commands::Log(format!("Pinned server key {} for {}", server_key, server_address)))?;
+                    self.config.pinned_server_keys.insert(server_address.clone(), server_key.clone());
+                    self.app_tx.send(Commands::PinServerKey(server_address, server_key))?;
+                }
+
                 // dispatch batch loader
                 if let Some(stream) = &mut self.command_stream {
                     let request = Request {
                         request_type: RequestTypes::StartBatchProcessor,
-                        body: vec![],
+                        body: serde_json::to_vec(&Codec::supported())?,
                     };
 
                     send_request(request, stream)?;
 
                     let response = read_response(stream)?;
-                    self.log_response(&response)?;
-                    self.notify_app(&response)?;
+                    self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+
+                    let offer: BatchProcessorOffer = serde_json::from_slice(&response.body)?;
 
-                    let file_streaming_service = String::from_utf8_lossy(&response.body).to_string();
-                    let mut file_stream = TcpStream::connect(file_streaming_service)?;
+                    // the data port lives on the same host we reached for the command connection
+                    let mut data_address = stream.peer_addr()?;
+                    data_address.set_port(offer.port);
+                    let mut file_stream = self.secure_stream(TcpStream::connect(data_address)?)?;
+                    let tls_fingerprint = self.config.pinned_certificates.get(&self.config.server_address)
+                        .filter(|_| self.config.use_tls)
+                        .cloned();
 
                     // handshake to confirm connection .. blocking
+                    send_message(&DataSessionHello { session_token: offer.session_token.clone() }, &mut file_stream)?;
                     let response = read_response(&mut file_stream)?;
                     self.log_response(&response)?;
                     self.notify_app(&response)?;
+                    if response.status_code != ResponseCodes::OK {
+                        return Err(anyhow::anyhow!("{} | [ {} ]", response.status_code, String::from_utf8_lossy(&response.body)));
+                    }
                     
                     let app_tx_clone = self.app_tx.clone();
                     let stop_flag_clone = self.stop_flag.clone();
                     let (callback_tx, rx) = mpsc::channel::<BatchLoaderCallback>();
                     self.batch_loader_callback_rx = Some(rx);
-                    let mut batch_loader = BatchLoader::new(file_stream, stop_flag_clone, app_tx_clone, callback_tx);
+                    let mut batch_loader = BatchLoader::new(file_stream, stop_flag_clone, app_tx_clone, callback_tx, offer.codecs, tls_fingerprint, offer.session_token);
 
                     (self.batch_loader_job_tx, self.batch_loader_join_handle) = match batch_loader.listen() {
                         Ok((tx, join_handle)) => (Some(tx),Some(join_handle)),
@@ -98,7 +132,7 @@ impl Client {
 
                 // kill the client
                 if let Some(stream) = self.command_stream.as_mut() {
-                    stream.shutdown(std::net::Shutdown::Both)?;
+                    stream.shutdown()?;
                 }
                 self.app_tx.send(Commands::UpdateConnectionStatus(ConnectionStatus::Disconnected))?;
                 self.app_tx.send(Commands::Notify("Client stopped.".to_string()))?;
@@ -114,6 +148,45 @@ impl Client {
         }
         Ok(())
     }
+    // wraps a fresh connection in tls when it's enabled, pinning the server's certificate the first time we see it
+    fn secure_stream(&mut self, tcp:TcpStream) -> anyhow::Result<Stream> {
+        if !self.config.use_tls {
+            return Ok(Stream::Plain(tcp));
+        }
+
+        let server_address = self.config.server_address.clone();
+        let server_host = server_address.split(':').next().unwrap_or_default().to_string();
+        let pinned_fingerprint = self.config.pinned_certificates.get(&server_address).cloned();
+
+        let (stream, fingerprint) = tls::connect(tcp, &server_host, pinned_fingerprint.clone())?;
+        if pinned_fingerprint.is_none() {
+            let message = format!("Pinned certificate {} for {}", fingerprint, server_address);
+            self.app_tx.send(Commands::Log(message.clone()))?;
+            self.app_tx.send(Commands::Notify(message))?;
+            self.config.pinned_certificates.insert(server_address.clone(), fingerprint.clone());
+            self.app_tx.send(Commands::PinCertificate(server_address, fingerprint))?;
+        }
+        Ok(stream)
+    }
+
+    // proves this device holds its key and checks the server holds the key we pinned the first time, returns the server's key
+    fn authenticate(&mut self) -> anyhow::Result<String> {
+        let identity = Identity::load_or_generate("photo-client-identity.key")?;
+        let pinned_server_key = self.config.pinned_server_keys.get(&self.config.server_address).cloned();
+
+        match &mut self.command_stream {
+            Some(stream) => auth::authenticate_with_server(
+                stream,
+                &identity,
+                &self.config.device_name,
+                &self.config.user_name,
+                &self.config.enrollment_code,
+                pinned_server_key.as_ref(),
+            ),
+            None => Err(anyhow::anyhow!("not connected")),
+        }
+    }
+
     fn app_request_handler(&mut self) -> anyhow::Result<()> {
         while !self.stop_flag.load(atomic::Ordering::Relaxed) {
             match self.app_rx.try_recv() {
@@ -123,12 +196,23 @@ impl Client {
                         Commands::CreateRepo(msg) => self.create_repository(msg.to_string())?,
                         Commands::GetRepoTree(repo_name) => self.get_repo_tree(repo_name)?,
                         Commands::SetStoragePath(storage_directory) => self.set_storage_path(storage_directory)?,
+                        Commands::GetStoreStats => self.get_store_stats()?,
+                        Commands::GetPendingDevices => self.get_pending_devices()?,
+                        Commands::ApproveDevice(approval) => self.approve_device(approval)?,
                         Commands::StartEventListener(repo_name, watch_directory) => {
                             let stop_flag = std::sync::Arc::new(atomic::AtomicBool::new(false));
                             let file_streaming_client_handle = self.start_event_listener(repo_name.to_string(), watch_directory, stop_flag.clone())?;
                             self.repo_threads.insert(repo_name, (file_streaming_client_handle, stop_flag));
                         }
                         Commands::DisconnectStream(repo) => self.disconnect_repository(&repo)?,
+                        Commands::RestoreRepository(repo_name, directory, destination) => self.restore_repository(repo_name, directory, destination)?,
+                        Commands::SetRepoAccess(access) => self.set_repo_access(access)?,
+                        Commands::DownloadFile(repo_name, relative_path, destination) => self.download_file(repo_name, relative_path, destination)?,
+                        Commands::GetFileMetadata(repo_name, relative_path) => self.get_file_metadata(repo_name, relative_path)?,
+                        Commands::Search(query) => self.search(query)?,
+                        Commands::GetRepoStats(repo_name) => self.get_repo_stats(repo_name)?,
+                        Commands::SetRepoQuota(update) => self.set_repo_quota(update)?,
+                        Commands::GetThumbnail(repo_name, relative_path, hash, size) => self.get_thumbnail(repo_name, relative_path, hash, size)?,
                         Commands::RemoveRepository(repo) => {
                             self.disconnect_repository(&repo)?;
                             self.remove_repository(&repo)?;
@@ -168,6 +252,67 @@ impl Client {
         Ok(())
     }
     
+    fn get_pending_devices(&mut self) -> anyhow::Result<()> {
+        if let Some(stream) = &mut self.command_stream {
+            let request = Request {
+                request_type: RequestTypes::GetPendingDevices,
+                body: Vec::new(),
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+            self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+
+            match response.status_code {
+                ResponseCodes::OK | ResponseCodes::Empty => {
+                    let devices: Vec<PendingDevice> = serde_json::from_slice(&response.body)?;
+                    self.app_tx.send(Commands::PostPendingDevices(devices))?;
+                }
+                _ => self.notify_app(&response)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn approve_device(&mut self, approval:DeviceApproval) -> anyhow::Result<()> {
+        if let Some(stream) = &mut self.command_stream {
+            let request = Request {
+                request_type: RequestTypes::ApproveDevice,
+                body: serde_json::to_vec(&approval)?,
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+            self.log_response(&response)?;
+            self.notify_app(&response)?;
+        }
+        self.get_pending_devices()
+    }
+
+    fn get_store_stats(&mut self) -> anyhow::Result<()> {
+        if let Some(stream) = &mut self.command_stream {
+            let request = Request {
+                request_type: RequestTypes::GetStoreStats,
+                body: Vec::new(),
+            };
+
+            send_request(request, stream)?;
+            let response = read_response(stream)?;
+
+            if response.status_code == ResponseCodes::OK {
+                let stats: StoreStats = serde_json::from_slice(&response.body)?;
+                let message = format!("{} blobs, {} files | {} bytes stored for {} bytes of files, {} bytes saved by deduplication",
+                    stats.blob_count, stats.reference_count, stats.stored_bytes, stats.logical_bytes, stats.saved_bytes);
+                self.app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, message)))?;
+                self.app_tx.send(Commands::Notify(message))?;
+            } else {
+                self.log_response(&response)?;
+                self.notify_app(&response)?;
+            }
+        }
+        Ok(())
+    }
+
     fn start_event_listener(&mut self, repo_name:String, watch_directory:String, stop_flag: Arc<atomic::AtomicBool>) -> anyhow::Result<JoinHandle<()>> {
         if let (Some(batch_loader_tx), Some(repo_config)) = (self.batch_loader_job_tx.clone(), self.config.repo_config.get(&repo_name)) {
             let track_modifications = repo_config.track_modifications.clone();
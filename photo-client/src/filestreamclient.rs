@@ -1,7 +1,6 @@
-use std::{io::prelude::*, net::TcpStream, path::{Path,PathBuf}, sync::{Arc, atomic, mpsc}, thread::JoinHandle, time::Duration};
-use bincode::{config, encode_into_slice};
-use notify::{Watcher,RecommendedWatcher, RecursiveMode, EventKind};
-use shared::{read_response, FileHeader, Job, BatchJob};
+use std::{collections::HashMap, io::prelude::*, net::{SocketAddr, TcpStream}, path::{Path,PathBuf}, sync::{Arc, atomic, mpsc}, thread::JoinHandle, time::Duration};
+use notify::{Watcher,RecommendedWatcher, RecursiveMode, EventKind, event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode}};
+use shared::{tls, tree::sanitize_relative_path, hash_bytes, upload_id, Stream, read_response, send_message, send_upload, Codec, CHUNK_SIZE, DataSessionHello, FileHeader, FileResult, Job, BatchJob, Response, ResponseCodes, TreeChange, TreeOperation};
 use std::{fs, thread::sleep};
 
 use crate::app::{Commands};
@@ -12,8 +11,12 @@ pub struct RepoEventListener {
     batch_job_tx:mpsc::Sender<BatchJob>,
     stop_flag:Arc<atomic::AtomicBool>,
     track_modifications:bool,
+    uploaded_hashes: HashMap<PathBuf, String>, // what each path held when it was last sent, one change often raises several events
 }
 
+// only inotify reports when a write to a file finishes, elsewhere every data change raises an event
+const CLOSE_WRITE_EVENTS: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
 pub enum BatchLoaderCallback {
     Done,
     Failed,
@@ -21,16 +24,19 @@ pub enum BatchLoaderCallback {
 
 
 pub struct BatchLoader {
-    stream:Option<TcpStream>, // communicates with the server
+    stream:Option<Stream>, // communicates with the server
     stop_flag: Option<Arc<atomic::AtomicBool>>,
     rx: Option<mpsc::Receiver<BatchJob>>,
     pub tx: mpsc::Sender<BatchJob>,
     pub callback_tx:Option<mpsc::Sender<BatchLoaderCallback>>,
     pub app_tx:Option<mpsc::Sender<Commands>>,
+    codecs: Vec<Codec>, // agreed with the server when the batch processor started
+    tls_fingerprint: Option<String>, // set when the file stream is encrypted, reconnects must present the same certificate
+    session_token: String, // lets a reconnect claim the same batch processor
 }
 
 impl BatchLoader {
-    pub fn new(stream:TcpStream, stop_flag: Arc<atomic::AtomicBool>, app_tx:mpsc::Sender<Commands>, callback_tx:mpsc::Sender<BatchLoaderCallback>) -> Self {
+    pub fn new(stream:Stream, stop_flag: Arc<atomic::AtomicBool>, app_tx:mpsc::Sender<Commands>, callback_tx:mpsc::Sender<BatchLoaderCallback>, codecs:Vec<Codec>, tls_fingerprint:Option<String>, session_token:String) -> Self {
         let (tx, rx) = mpsc::channel::<BatchJob>();
 
         BatchLoader {
@@ -39,6 +45,9 @@ impl BatchLoader {
             rx: Some(rx),tx,
             callback_tx: Some(callback_tx),
             app_tx: Some(app_tx),
+            codecs,
+            tls_fingerprint,
+            session_token,
         }
     }
 
@@ -53,38 +62,62 @@ impl BatchLoader {
         let mut stream = self.stream.take().expect("stream not given");
         let app_tx = self.app_tx.take().expect("app tx not given");
         let callback_tx = self.callback_tx.take().expect("callback tx not given");
+        let address = stream.peer_addr()?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        let codecs = self.codecs.clone();
+        let tls_fingerprint = self.tls_fingerprint.clone();
+        let session_token = self.session_token.clone();
 
         let join_handle:JoinHandle<anyhow::Result<()>> = std::thread::spawn(move || {
             while !stop_flag.load(atomic::Ordering::Relaxed) {
                 match rx.try_recv() {
-                    Ok(batch_job) => {
-                        let chunk_size = 1024 * 1024 * 1;
-
-                        let batch_size = batch_job.jobs.len() as u32;
-                        stream.write_all(&batch_size.to_be_bytes())?;
-
-                        for job in batch_job.jobs {
-                            let mut header_bytes = vec![0u8;1024];
-                            let header_size = encode_into_slice(&job.file_header, &mut header_bytes, config::standard())? as u32;
-                            header_bytes.truncate(header_size as usize);
-                            stream.write_all(&header_size.to_be_bytes())?;
-                            stream.write_all(&header_bytes)?;
-                            let mut remaining = &job.data[..];
-
-                            while !remaining.is_empty() {
-                                let take = std::cmp::min(remaining.len(), chunk_size);
-                                let chunk = &remaining[..take];
-                                stream.write_all(&(take as u32).to_be_bytes())?;
-                                stream.write_all(&chunk)?;
-                                remaining = &remaining[take..];
-                            }
-                            stream.write_all(&0u32.to_be_bytes())?;
+                    Ok(mut batch_job) => {
+                        for job in batch_job.jobs.iter_mut() {
+                            job.file_header.codec = choose_codec(job, &codecs);
                         }
 
-                        let response = read_response(&mut stream)?;
-                        let response_message: std::borrow::Cow<'_, str> = String::from_utf8_lossy(&response.body);
-                        app_tx.send(Commands::Notify(format!("{}", response_message)))?;
-                        app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response_message)))?;
+                        let mut retries = 0;
+                        loop {
+                            let mut attempts = 0;
+                            let response = loop {
+                                match send_batch(&batch_job, &mut stream) {
+                                    Ok(response) => break response,
+                                    Err(e) if attempts < MAX_RESUME_ATTEMPTS => {
+                                        attempts += 1;
+                                        app_tx.send(Commands::Log(format!("file stream dropped ({}), resuming upload attempt {}", e, attempts)))?;
+                                        sleep(Duration::from_secs(attempts));
+                                        if let Ok(resumed_stream) = reconnect(&address, &tls_fingerprint, &session_token) {
+                                            stream = resumed_stream;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        callback_tx.send(BatchLoaderCallback::Failed)?;
+                                        return Err(e);
+                                    }
+                                }
+                            };
+                            app_tx.send(Commands::Log(format!("{} | [ {} ]", response.status_code, response.status_message)))?;
+
+                            let results: Vec<FileResult> = serde_json::from_slice(&response.body).unwrap_or_default();
+                            for result in &results {
+                                app_tx.send(Commands::Log(format!("{} | [ {} {} ]", result.status_code, result.file_location, result.message)))?;
+                            }
+
+                            // resend only the files that might go through on another try, results come back in upload order
+                            // followed by one for each change, which are applied once and never retried
+                            let retry: Vec<bool> = results.iter().take(batch_job.jobs.len()).map(|r| r.is_retryable()).collect();
+                            if results.len() < batch_job.jobs.len() || !retry.contains(&true) || retries >= MAX_FILE_RETRIES {
+                                app_tx.send(Commands::Notify(summarize_results(&results)))?;
+                                break;
+                            }
+                            retries += 1;
+                            let jobs = batch_job.jobs.into_iter()
+                                .zip(retry)
+                                .filter_map(|(job, retry)| retry.then_some(job))
+                                .collect();
+                            batch_job = BatchJob::new(jobs);
+                            app_tx.send(Commands::Log(format!("retrying {} files, attempt {}", batch_job.jobs.len(), retries)))?;
+                        }
                         
                         callback_tx.send(BatchLoaderCallback::Done)?;
 
@@ -97,7 +130,7 @@ impl BatchLoader {
                 }
             }
 
-            stream.shutdown(std::net::Shutdown::Both).ok();
+            stream.shutdown().ok();
             let message = "Streaming client stopped.".to_string();
             app_tx.send(Commands::Log(message.clone()))?;
             app_tx.send(Commands::Notify(message.clone()))?;
@@ -109,6 +142,89 @@ impl BatchLoader {
     }
 }
 
+// the server mirrors this path below the repository, eg. /home/me/Pictures/DCIM/a.jpg -> DCIM/a.jpg for /home/me/Pictures
+// sanitized the way the server stores it, so a file with eg. a ':' in its name is found in the tree
+pub fn relative_path(watch_directory:&Path, file_path:&Path) -> String {
+    let relative = file_path.strip_prefix(watch_directory).unwrap_or(file_path);
+    let relative = relative.components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join("/");
+    sanitize_relative_path(&relative).unwrap_or(relative)
+}
+
+const MAX_RESUME_ATTEMPTS: u64 = 5;
+// long enough for the server to store a whole batch, a server that goes quiet for longer is reconnected to
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_FILE_RETRIES: u32 = 2;
+
+fn summarize_results(results:&[FileResult]) -> String {
+    let failed: Vec<&FileResult> = results.iter().filter(|r| r.status_code != ResponseCodes::OK).collect();
+    let mut summary = format!("stored {} of {} files", results.len() - failed.len(), results.len());
+    for result in failed {
+        summary.push_str(&format!("\n{} | {}", result.status_code, result.message));
+    }
+    summary
+}
+
+// formats that are already compressed gain nothing from another pass
+const COMPRESSED_EXTENSIONS: [&str; 14] = [
+    "jpg", "jpeg", "heic", "heif", "webp", "avif", "gif",
+    "mp4", "mov", "mkv", "zip", "gz", "7z", "zst",
+];
+const MIN_COMPRESSION_RATIO: f64 = 0.9;
+
+fn choose_codec(job:&Job, codecs:&[Codec]) -> Codec {
+    let codec = match codecs.first() {
+        Some(codec) => *codec,
+        None => return Codec::None,
+    };
+
+    let file_ext = job.file_header.file_ext.to_lowercase();
+    if COMPRESSED_EXTENSIONS.contains(&file_ext.as_str()) {
+        return Codec::None;
+    }
+
+    // measure the ratio on a sample before paying for the whole file
+    let sample = &job.data[..std::cmp::min(job.data.len(), CHUNK_SIZE)];
+    match codec.compress(sample) {
+        Ok(compressed) if !sample.is_empty() && (compressed.len() as f64) < sample.len() as f64 * MIN_COMPRESSION_RATIO => codec,
+        _ => Codec::None,
+    }
+}
+
+fn send_batch(batch_job:&BatchJob, stream:&mut Stream) -> anyhow::Result<Response> {
+    let batch_size = batch_job.jobs.len() as u32;
+    stream.write_all(&batch_size.to_be_bytes())?;
+
+    // the server skips whatever it already holds of each file
+    for job in &batch_job.jobs {
+        send_upload(job, stream)?;
+    }
+    send_message(&batch_job.changes, stream)?;
+
+    Ok(read_response(stream)?)
+}
+
+fn reconnect(address:&SocketAddr, tls_fingerprint:&Option<String>, session_token:&str) -> anyhow::Result<Stream> {
+    let tcp = TcpStream::connect(address)?;
+    let mut stream = match tls_fingerprint {
+        Some(fingerprint) => tls::connect(tcp, &address.ip().to_string(), Some(fingerprint.clone()))?.0,
+        None => Stream::Plain(tcp),
+    };
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    // handshake to confirm the batch processor took us back
+    send_message(&DataSessionHello { session_token: session_token.to_string() }, &mut stream)?;
+    let response = read_response(&mut stream)?;
+    if response.status_code != ResponseCodes::OK {
+        return Err(anyhow::anyhow!("{} | [ {} ]", response.status_code, String::from_utf8_lossy(&response.body)));
+    }
+    Ok(stream)
+}
+
 impl RepoEventListener {
     pub fn new(
             repo_name: String,
@@ -122,6 +238,7 @@ impl RepoEventListener {
             batch_job_tx,
             stop_flag,
             track_modifications,
+            uploaded_hashes: HashMap::new(),
         }
     }
 
@@ -155,36 +272,8 @@ impl RepoEventListener {
                             continue;
                         }
                     };
-                    if let EventKind::Create(notify::event::CreateKind::File) = new_event.kind {
-                        for path in new_event.paths.clone() {
-
-                            let file_ext = path.extension()
-                                .and_then(|s| s.to_str())
-                                .unwrap_or("unknown");
-                            if file_ext == "part" {
-                                continue
-                            }
-                            
-                            loop {
-                                match is_file_stable(&path, 100, 10) {
-                                    Ok(true) => break, // File is stable, exit loop
-                                    Ok(false) => {
-                                        // File not stable yet, wait and try again
-                                        std::thread::sleep(Duration::from_millis(1000));
-                                        continue;
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Error checking file: {:?}", e);
-                                        // Decide whether to continue or break
-                                        break; // or return an error
-                                    }
-                                }
-                            }
-                            if let Err(e) = self.submit_job(path, self.repo_name.clone()) {
-                                eprintln!("Failed to send image: {}", e);
-                                break;
-                            };
-                        }
+                    if let Err(e) = self.handle_event(new_event) {
+                        eprintln!("Failed to send changes: {}", e);
                     }
                 },
                 Err(mpsc::TryRecvError::Empty) => {
@@ -199,6 +288,82 @@ impl RepoEventListener {
         Ok(())
     }
 
+    // new files are uploaded, with track_modifications a file is uploaded again whenever a write to it finishes
+    // or, without close-write events, once its data stops changing, deletions and renames only update the server's tree
+    fn handle_event(&mut self, event:notify::Event) -> anyhow::Result<()> {
+        let watch_directory = PathBuf::from(&self.watch_directory);
+        let is_upload = match event.kind {
+            // some platforms can't tell a file from a directory when it's created, upload_when_stable skips directories
+            EventKind::Create(CreateKind::File | CreateKind::Any) => true,
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => self.track_modifications,
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => self.track_modifications && !CLOSE_WRITE_EVENTS,
+            _ => false,
+        };
+
+        if is_upload {
+            for path in event.paths {
+                self.upload_when_stable(path)?;
+            }
+            return Ok(());
+        }
+
+        let operation = match (event.kind, event.paths.as_slice()) {
+            (EventKind::Remove(_), [path]) if path != &watch_directory && !is_part_file(path) => TreeOperation::Remove { path: relative_path(&watch_directory, path) },
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                // downloads are usually written to a .part file and renamed once they are complete
+                if is_part_file(from) {
+                    return self.upload_when_stable(to.clone());
+                }
+                TreeOperation::Rename {
+                    from: relative_path(&watch_directory, from),
+                    to: relative_path(&watch_directory, to),
+                }
+            }
+            _ => return Ok(()),
+        };
+        // a path that is recreated with the same contents still has to be sent
+        if let TreeOperation::Remove { .. } | TreeOperation::Rename { .. } = &operation {
+            for path in &event.paths {
+                self.uploaded_hashes.retain(|uploaded_path, _| !uploaded_path.starts_with(path));
+            }
+        }
+
+        let change = TreeChange { repo_name: self.repo_name.clone(), operation };
+        self.batch_job_tx.send(BatchJob::from_changes(vec![change]))?;
+        Ok(())
+    }
+
+    fn upload_when_stable(&mut self, path:PathBuf) -> anyhow::Result<()> {
+        if is_part_file(&path) || !path.is_file() {
+            return Ok(());
+        }
+
+        loop {
+            // the file can be deleted or moved away while it's still being written
+            if !path.is_file() {
+                println!("{} went away before it finished changing, skipping it", path.display());
+                return Ok(());
+            }
+            if self.stop_flag.load(atomic::Ordering::Relaxed) {
+                return Ok(());
+            }
+            match is_file_stable(&path, 100, 10) {
+                Ok(true) => break, // File is stable, exit loop
+                Ok(false) => {
+                    // File not stable yet, wait and try again
+                    std::thread::sleep(Duration::from_millis(1000));
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error checking file: {:?}", e);
+                    // Decide whether to continue or break
+                    break; // or return an error
+                }
+            }
+        }
+        self.submit_job(path, self.repo_name.clone())
+    }
+
     fn prepare_job(&self, local_path:PathBuf, repo_name: String) -> anyhow::Result<BatchJob> {
         if !local_path.exists() {
             return Err(anyhow::anyhow!("File not found"));
@@ -234,13 +399,19 @@ impl RepoEventListener {
             .to_string_lossy()
             .into_owned();
 
+        let file_hash = hash_bytes(&file_bytes);
+
         let file_header = FileHeader {
+            upload_id: upload_id(&repo_name, &file_location, &file_hash),
             repo_name: repo_name,
             file_name: file_name.to_string(), 
             file_size: file_bytes.len() as usize,
+            relative_path: relative_path(Path::new(&self.watch_directory), &local_path),
             file_location: file_location,
             file_ext: file_ext.to_string(),
             file_datetime: file_datetime,
+            file_hash,
+            codec: Codec::None, // the batch loader picks one from what the server agreed to
         };
 
         println!("File size: {} bytes", file_bytes.len());
@@ -254,13 +425,23 @@ impl RepoEventListener {
         Ok(BatchJob::new(vec![job]))
     }
 
+    // contents that were already sent from this path are skipped, so a write that raised several events is only uploaded once
     fn submit_job(&mut self, local_path:PathBuf, repo_name:String) -> anyhow::Result<()> {
-        let batch_job = self.prepare_job(local_path, repo_name)?;
+        let batch_job = self.prepare_job(local_path.clone(), repo_name)?;
+        let file_hash = batch_job.jobs.first().map(|job| job.file_header.file_hash.clone()).unwrap_or_default();
+        if self.uploaded_hashes.get(&local_path) == Some(&file_hash) {
+            return Ok(());
+        }
         self.batch_job_tx.send(batch_job)?;
+        self.uploaded_hashes.insert(local_path, file_hash);
         Ok(())
     }
 }
 
+fn is_part_file(path: &Path) -> bool {
+    path.extension().is_some_and(|file_ext| file_ext == "part")
+}
+
 fn is_file_stable(path: &Path, check_interval_ms: u64, stability_checks: u32) -> anyhow::Result<bool> {
     let mut previous_size = match fs::metadata(path).map(|m| m.len()) {
         Ok(size) => size,
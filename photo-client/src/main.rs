@@ -13,7 +13,7 @@ fn main() -> std::io::Result<()> {
     if Path::new(config_path).exists() {
         config = ClientConfig::load_from_file(config_path);
     } else {
-        config = ClientConfig { server_address: "".to_string(), server_storage_directory: "".to_string(), repo_config: HashMap::new()};
+        config = ClientConfig { server_address: "".to_string(), server_storage_directory: "".to_string(), repo_config: HashMap::new(), use_tls: false, pinned_certificates: HashMap::new(), device_name: "photo-client".to_string(), user_name: String::new(), pinned_server_keys: HashMap::new(), enrollment_code: String::new()};
     }
     let (tx, rx) = mpsc::channel::<Commands>();
 
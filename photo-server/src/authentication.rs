@@ -0,0 +1,120 @@
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use shared::{
+    auth::{client_transcript, new_nonce, server_transcript, verify, AuthChallenge, AuthHello, AuthProof, Identity},
+    read_message, send_message, send_response, PendingDevice, Response, ResponseCodes, Stream,
+};
+use crate::request_handler::request_handler_utils::{update_config, EnrolledDevice, SharedConfig};
+
+const MAX_ENROLLMENT_FAILURES: u32 = 5;
+const ENROLLMENT_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+// wrong enrollment codes per address, the code is short so an address that keeps guessing is locked out for a while
+#[derive(Clone, Default)]
+pub struct EnrollmentLimiter {
+    failures: Arc<Mutex<HashMap<IpAddr, (u32, Instant)>>>, // failed attempts and when the last one was made
+}
+
+impl EnrollmentLimiter {
+    // how much longer the address has to wait before it may try another code, None when it may try now
+    fn locked_out(&self, address:IpAddr) -> Option<Duration> {
+        let failures = self.failures.lock().ok()?;
+        let (count, last_failure) = failures.get(&address)?;
+        let remaining = ENROLLMENT_LOCKOUT.checked_sub(last_failure.elapsed())?;
+        (*count >= MAX_ENROLLMENT_FAILURES).then_some(remaining)
+    }
+
+    fn record_failure(&self, address:IpAddr) {
+        if let Ok(mut failures) = self.failures.lock() {
+            // addresses that stopped guessing long enough ago start over
+            failures.retain(|_, (_, last_failure)| last_failure.elapsed() < ENROLLMENT_LOCKOUT);
+            let (count, last_failure) = failures.entry(address).or_insert((0, Instant::now()));
+            *count += 1;
+            *last_failure = Instant::now();
+        }
+    }
+
+    fn clear(&self, address:IpAddr) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(&address);
+        }
+    }
+}
+
+// the server side of the challenge-response, returns the device once it has proven it holds an enrolled key
+pub fn authenticate_device(stream:&mut Stream, identity:&Identity, shared_config:&SharedConfig, enrollment_limiter:&EnrollmentLimiter) -> anyhow::Result<Option<EnrolledDevice>> {
+    let hello: AuthHello = read_message(stream)?;
+
+    let nonce = new_nonce();
+    let challenge = AuthChallenge {
+        public_key: identity.public_key(),
+        signature: identity.sign(&server_transcript(&hello.nonce, &nonce)),
+        nonce,
+    };
+    send_message(&challenge, stream)?;
+
+    let proof: AuthProof = read_message(stream)?;
+    if let Err(e) = verify(&hello.public_key, &client_transcript(&hello.nonce, &challenge.nonce), &proof.signature) {
+        reject(stream, format!("{} failed to prove it holds its key | {}", hello.device_name, e))?;
+        return Ok(None);
+    }
+
+    let config = shared_config.lock()
+        .map_err(|e| anyhow::anyhow!("config lock poisoned: {}", e))?
+        .clone();
+    if let Some(device) = config.enrolled_device(&hello.public_key) {
+        // an enrolled device always acts as the user it was enrolled under, whatever it claims now
+        return Ok(Some(device.clone()));
+    }
+
+    let address = stream.peer_addr()?.ip();
+    if let Some(remaining) = enrollment_limiter.locked_out(address) {
+        reject(stream, format!("{} entered too many wrong enrollment codes, try again in {} minutes", address, remaining.as_secs() / 60 + 1))?;
+        return Ok(None);
+    }
+    if config.enrollment_code.is_empty() || hello.enrollment_code != config.enrollment_code {
+        if !hello.enrollment_code.is_empty() {
+            enrollment_limiter.record_failure(address);
+        }
+        reject(stream, format!("{} is not enrolled, enter the enrollment code shown on the server to enroll it", hello.device_name))?;
+        return Ok(None);
+    }
+    enrollment_limiter.clear(address);
+    let user = if hello.user_name.trim().is_empty() { hello.device_name.clone() } else { hello.user_name.trim().to_string() };
+    // the code is shared, so it alone can't be enough to join an account that already exists along with its roles
+    // decided under the config lock so two devices enrolling under the same new user can't both skip approval
+    let (pending, _) = update_config(shared_config, |config| {
+        if config.user_exists(&user) {
+            config.add_pending_device(PendingDevice {
+                device_name: hello.device_name.clone(),
+                user_name: user.clone(),
+                public_key: hello.public_key.clone(),
+            });
+            true
+        } else {
+            config.enroll_device(hello.device_name.clone(), hello.public_key.clone(), user.clone());
+            false
+        }
+    })?;
+    if pending {
+        reject(stream, format!("{} already exists, approve {} from one of its devices or ask a server admin to", user, hello.device_name))?;
+        return Ok(None);
+    }
+    println!("Enrolled new device {} for {} ({})", hello.device_name, user, hello.public_key);
+
+    Ok(Some(EnrolledDevice {
+        name: hello.device_name,
+        public_key: hello.public_key,
+        user,
+    }))
+}
+
+fn reject(stream:&mut Stream, message:String) -> anyhow::Result<()> {
+    println!("{}", message);
+    let response = Response {
+        status_code: ResponseCodes::Unauthorized,
+        status_message: "Unauthorized".to_string(),
+        body: message.as_bytes().to_vec(),
+    };
+    send_response(response, stream)?;
+    Ok(())
+}
@@ -0,0 +1,434 @@
+use std::{path::Path, time::{SystemTime, UNIX_EPOCH}};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+use shared::{media_type, FileEntry, FileMetadata, MediaMetadata, RepoQuota, RepoStats, SearchQuery, SearchResults, StatsBucket};
+use crate::media_metadata::read_media_metadata;
+use crate::tree_store::TreeStore;
+
+// one stored file as the catalog knows it, timestamps are unix seconds
+#[derive(Debug, Clone, Default)]
+pub struct FileRecord {
+    pub repo_name: String,
+    pub relative_path: String,
+    pub size: u64,
+    pub hash: String,
+    pub uploaded: i64,
+    pub created: i64, // when the file was created on the device that uploaded it
+    pub device: String, // empty for records rebuilt from the trees
+    pub file_location: String,
+    pub media_type: String, // image, video or other, going by the extension
+    pub media: MediaMetadata, // read from the stored copy's exif
+}
+
+pub fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+impl FileRecord {
+    // reads what it can about the stored copy, the tree entry provides the rest
+    pub fn new(repo_name: &str, relative_path: &str, entry: &FileEntry, stored_path: &Path, device: &str) -> Self {
+        let media_type = media_type(relative_path);
+
+        FileRecord {
+            repo_name: repo_name.to_string(),
+            relative_path: relative_path.to_string(),
+            size: entry.size,
+            hash: entry.hash.clone(),
+            uploaded: unix_seconds(entry.uploaded),
+            created: unix_seconds(entry.created),
+            device: device.to_string(),
+            file_location: entry.file_location.clone(),
+            media_type: media_type.to_string(),
+            media: read_media_metadata(stored_path, media_type),
+        }
+    }
+
+    pub fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            repo_name: self.repo_name.clone(),
+            relative_path: self.relative_path.clone(),
+            size: self.size,
+            hash: self.hash.clone(),
+            uploaded: self.uploaded,
+            created: self.created,
+            device: self.device.clone(),
+            media_type: self.media_type.clone(),
+            media: self.media.clone(),
+        }
+    }
+}
+
+const FILE_COLUMNS: &str = "repo_name, relative_path, size, hash, uploaded, created, device, file_location, media_type, width, height,
+    captured, camera_make, camera_model, lens, exposure_time, f_number, iso, focal_length, orientation, latitude, longitude, altitude";
+
+fn read_file(row: &Row) -> rusqlite::Result<FileRecord> {
+    Ok(FileRecord {
+        repo_name: row.get(0)?,
+        relative_path: row.get(1)?,
+        size: row.get::<_, i64>(2)? as u64,
+        hash: row.get(3)?,
+        uploaded: row.get(4)?,
+        created: row.get(5)?,
+        device: row.get(6)?,
+        file_location: row.get(7)?,
+        media_type: row.get(8)?,
+        media: MediaMetadata {
+            width: row.get(9)?,
+            height: row.get(10)?,
+            captured: row.get(11)?,
+            camera_make: row.get(12)?,
+            camera_model: row.get(13)?,
+            lens: row.get(14)?,
+            exposure_time: row.get(15)?,
+            f_number: row.get(16)?,
+            iso: row.get(17)?,
+            focal_length: row.get(18)?,
+            orientation: row.get(19)?,
+            latitude: row.get(20)?,
+            longitude: row.get(21)?,
+            altitude: row.get(22)?,
+        },
+    })
+}
+
+// the file name and lowercase extension, kept in their own columns for searching
+fn file_name_and_extension(relative_path: &str) -> (String, String) {
+    let relative_path = Path::new(relative_path);
+    let file_name = relative_path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = relative_path.extension().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default();
+    (file_name, extension)
+}
+
+// a record for a path that is already cataloged replaces it
+fn insert_file(connection: &Connection, record: &FileRecord) -> rusqlite::Result<()> {
+    let media = &record.media;
+    let (file_name, extension) = file_name_and_extension(&record.relative_path);
+    connection.execute(&format!("
+        INSERT OR REPLACE INTO files ({}, file_name, extension)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25)", FILE_COLUMNS),
+        params![
+            record.repo_name, record.relative_path, record.size as i64, record.hash, record.uploaded, record.created,
+            record.device, record.file_location, record.media_type, media.width, media.height,
+            media.captured, media.camera_make, media.camera_model, media.lens, media.exposure_time, media.f_number,
+            media.iso, media.focal_length, media.orientation, media.latitude, media.longitude, media.altitude,
+            file_name, extension,
+        ])?;
+    Ok(())
+}
+
+const MAX_PAGE_SIZE: u32 = 200;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+
+// the where clause and its parameters for a search limited to the given repositories
+fn search_filter(query: &SearchQuery, repo_names: &[String]) -> (String, Vec<Value>) {
+    let mut conditions = Vec::<String>::new();
+    let mut parameters = Vec::<Value>::new();
+
+    let placeholders = vec!["?"; repo_names.len()].join(", ");
+    conditions.push(format!("repo_name IN ({})", placeholders));
+    parameters.extend(repo_names.iter().map(|repo_name| Value::Text(repo_name.clone())));
+
+    let name = query.name.trim();
+    if name.contains(['*', '?']) {
+        conditions.push("file_name GLOB ?".to_string());
+        parameters.push(Value::Text(name.to_string()));
+    } else if !name.is_empty() {
+        conditions.push("instr(lower(file_name), lower(?)) > 0".to_string());
+        parameters.push(Value::Text(name.to_string()));
+    }
+    if let Some(extension) = &query.extension {
+        conditions.push("extension = ?".to_string());
+        parameters.push(Value::Text(extension.trim_start_matches('.').to_lowercase()));
+    }
+    if let Some(min_size) = query.min_size {
+        conditions.push("size >= ?".to_string());
+        parameters.push(Value::Integer(min_size as i64));
+    }
+    if let Some(max_size) = query.max_size {
+        conditions.push("size <= ?".to_string());
+        parameters.push(Value::Integer(max_size as i64));
+    }
+    if let Some(captured_after) = query.captured_after {
+        conditions.push("coalesce(captured, created) >= ?".to_string());
+        parameters.push(Value::Integer(captured_after));
+    }
+    if let Some(captured_before) = query.captured_before {
+        conditions.push("coalesce(captured, created) < ?".to_string());
+        parameters.push(Value::Integer(captured_before));
+    }
+    if let Some(camera) = &query.camera {
+        conditions.push("instr(lower(coalesce(camera_make, '') || ' ' || coalesce(camera_model, '')), lower(?)) > 0".to_string());
+        parameters.push(Value::Text(camera.clone()));
+    }
+    (conditions.join(" AND "), parameters)
+}
+
+// everything the server knows about each stored file in a form that can be queried, the trees stay the
+// source of truth for what is stored and the catalog can be rebuilt from them at any time
+pub struct Catalog {
+    connection: Connection,
+}
+
+// bumped whenever the files table changes, older catalogs are dropped and rebuilt from the trees
+const SCHEMA_VERSION: i64 = 3;
+
+impl Catalog {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        let schema_version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if schema_version != SCHEMA_VERSION {
+            connection.execute_batch(&format!("
+                DROP TABLE IF EXISTS files;
+                PRAGMA user_version = {};", SCHEMA_VERSION))?;
+        }
+        connection.execute_batch("
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS files (
+                repo_name TEXT NOT NULL,
+                relative_path TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                uploaded INTEGER NOT NULL,
+                created INTEGER NOT NULL,
+                device TEXT NOT NULL,
+                file_location TEXT NOT NULL,
+                media_type TEXT NOT NULL,
+                width INTEGER,
+                height INTEGER,
+                captured INTEGER,
+                camera_make TEXT,
+                camera_model TEXT,
+                lens TEXT,
+                exposure_time TEXT,
+                f_number REAL,
+                iso INTEGER,
+                focal_length REAL,
+                orientation INTEGER,
+                latitude REAL,
+                longitude REAL,
+                altitude REAL,
+                file_name TEXT NOT NULL,
+                extension TEXT NOT NULL,
+                PRIMARY KEY (repo_name, relative_path)
+            );
+            CREATE INDEX IF NOT EXISTS files_by_hash ON files (hash);
+            CREATE INDEX IF NOT EXISTS files_by_capture_time ON files (repo_name, coalesce(captured, created));
+        ")?;
+        Ok(Catalog { connection })
+    }
+
+    pub fn record_file(&mut self, record: &FileRecord) -> rusqlite::Result<()> {
+        insert_file(&self.connection, record)
+    }
+
+    pub fn file(&self, repo_name: &str, relative_path: &str) -> rusqlite::Result<Option<FileRecord>> {
+        self.connection.query_row(
+            &format!("SELECT {} FROM files WHERE repo_name = ?1 AND relative_path = ?2", FILE_COLUMNS),
+            params![repo_name, relative_path],
+            read_file).optional()
+    }
+
+    // one page of the files in repo_names that match the query, newest first
+    pub fn search(&self, query: &SearchQuery, repo_names: &[String]) -> rusqlite::Result<SearchResults> {
+        let page_size = match query.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+        let mut results = SearchResults {
+            page: query.page,
+            page_size,
+            ..SearchResults::default()
+        };
+        if repo_names.is_empty() {
+            return Ok(results);
+        }
+
+        let (filter, mut parameters) = search_filter(query, repo_names);
+        results.total = self.connection.query_row(
+            &format!("SELECT COUNT(*) FROM files WHERE {}", filter),
+            params_from_iter(parameters.iter()),
+            |row| row.get::<_, i64>(0))? as u64;
+
+        parameters.push(Value::Integer(page_size as i64));
+        parameters.push(Value::Integer(query.page as i64 * page_size as i64));
+        let mut statement = self.connection.prepare(&format!("
+            SELECT {} FROM files WHERE {}
+            ORDER BY coalesce(captured, created) DESC, repo_name, relative_path
+            LIMIT ? OFFSET ?", FILE_COLUMNS, filter))?;
+        results.files = statement.query_map(params_from_iter(parameters.iter()), read_file)?
+            .map(|record| record.map(|record| record.metadata()))
+            .collect::<rusqlite::Result<Vec<FileMetadata>>>()?;
+        Ok(results)
+    }
+
+    // the repository's files grouped by group_by, which has to be an sql expression over the files table
+    fn stats_buckets(&self, repo_name: &str, group_by: &str, order_by: &str) -> rusqlite::Result<Vec<StatsBucket>> {
+        let mut statement = self.connection.prepare(&format!("
+            SELECT coalesce({}, '') AS label, COUNT(*) AS file_count, SUM(size) AS bytes FROM files
+            WHERE repo_name = ?1
+            GROUP BY label
+            ORDER BY {}", group_by, order_by))?;
+        statement.query_map(params![repo_name], |row| Ok(StatsBucket {
+            label: row.get(0)?,
+            file_count: row.get::<_, i64>(1)? as u64,
+            bytes: row.get::<_, i64>(2)? as u64,
+        }))?.collect()
+    }
+
+    pub fn repo_stats(&self, repo_name: &str) -> rusqlite::Result<RepoStats> {
+        let (file_count, total_bytes, last_upload) = self.connection.query_row("
+            SELECT COUNT(*), coalesce(SUM(size), 0), MAX(uploaded) FROM files WHERE repo_name = ?1",
+            params![repo_name],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64, row.get::<_, Option<i64>>(2)?)))?;
+
+        Ok(RepoStats {
+            repo_name: repo_name.to_string(),
+            file_count,
+            total_bytes,
+            last_upload,
+            by_extension: self.stats_buckets(repo_name, "extension", "file_count DESC, label")?,
+            by_month: self.stats_buckets(repo_name, "strftime('%Y-%m', coalesce(captured, created), 'unixepoch')", "label")?,
+            devices: self.stats_buckets(repo_name, "device", "file_count DESC, label")?,
+            quota: RepoQuota::default(), // the catalog doesn't know about quotas, the request handler fills it in
+        })
+    }
+
+    // drops a file, or a directory and everything below it
+    pub fn remove_path(&mut self, repo_name: &str, path: &str) -> rusqlite::Result<usize> {
+        self.connection.execute("
+            DELETE FROM files
+            WHERE repo_name = ?1 AND (relative_path = ?2 OR substr(relative_path, 1, length(?2) + 1) = ?2 || '/')",
+            params![repo_name, path])
+    }
+
+    // moves each record to its new path, anything already at a new path is replaced
+    pub fn rename_paths(&mut self, repo_name: &str, renamed_paths: &[(String, String)]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for (old_path, new_path) in renamed_paths {
+            transaction.execute("DELETE FROM files WHERE repo_name = ?1 AND relative_path = ?2", params![repo_name, new_path])?;
+            let (file_name, extension) = file_name_and_extension(new_path);
+            transaction.execute("
+                UPDATE files SET relative_path = ?3, file_name = ?4, extension = ?5
+                WHERE repo_name = ?1 AND relative_path = ?2",
+                params![repo_name, old_path, new_path, file_name, extension])?;
+        }
+        transaction.commit()
+    }
+
+    pub fn remove_repository(&mut self, repo_name: &str) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM files WHERE repo_name = ?1", params![repo_name])
+    }
+
+    pub fn file_count(&self) -> rusqlite::Result<u64> {
+        self.connection.query_row("SELECT COUNT(*) FROM files", [], |row| row.get::<_, i64>(0))
+            .map(|count| count as u64)
+    }
+
+    // replaces the whole catalog with what the trees and the stored files say, the uploading devices are lost
+    pub fn rebuild(&mut self, tree_store: &TreeStore, storage_directory: &Path) -> rusqlite::Result<u64> {
+        let mut records = Vec::<FileRecord>::new();
+        for (repo_name, tree) in tree_store.trees() {
+            for (relative_path, entry) in &tree.files {
+                let stored_path = storage_directory.join(repo_name).join(relative_path);
+                records.push(FileRecord::new(repo_name, relative_path, entry, &stored_path, ""));
+            }
+        }
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM files", [])?;
+        for record in &records {
+            insert_file(&transaction, record)?;
+        }
+        transaction.commit()?;
+        Ok(records.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(repo_name: &str, relative_path: &str, size: u64, created: i64, camera_model: Option<&str>) -> FileRecord {
+        FileRecord {
+            repo_name: repo_name.to_string(),
+            relative_path: relative_path.to_string(),
+            size,
+            hash: relative_path.to_string(),
+            created,
+            media_type: media_type(relative_path).to_string(),
+            media: MediaMetadata { camera_model: camera_model.map(str::to_string), ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    // newest first: DCIM/IMG_0003.JPG, DCIM/IMG_0002.jpg, clip.mp4, IMG_0001.jpg and the one in videos
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::open(":memory:").unwrap();
+        for record in [
+            record("photos", "IMG_0001.jpg", 1_000, 100, Some("Pixel 8")),
+            record("photos", "DCIM/IMG_0002.jpg", 5_000, 300, Some("EOS R6")),
+            record("photos", "DCIM/IMG_0003.JPG", 9_000, 400, None),
+            record("photos", "clip.mp4", 50_000, 200, Some("Pixel 8")),
+            record("videos", "IMG_0004.jpg", 1_000, 500, None),
+        ] {
+            catalog.record_file(&record).unwrap();
+        }
+        catalog
+    }
+
+    fn search(query: SearchQuery) -> (u64, Vec<String>) {
+        let results = catalog().search(&query, &["photos".to_string()]).unwrap();
+        (results.total, results.files.into_iter().map(|file| file.relative_path).collect())
+    }
+
+    #[test]
+    fn only_the_given_repositories_are_searched() {
+        assert_eq!(search(SearchQuery::default()).0, 4);
+        let results = catalog().search(&SearchQuery::default(), &[]).unwrap();
+        assert_eq!(results.total, 0);
+        let results = catalog().search(&SearchQuery::default(), &["photos".to_string(), "videos".to_string()]).unwrap();
+        assert_eq!(results.total, 5);
+    }
+
+    #[test]
+    fn names_match_parts_or_globs() {
+        assert_eq!(search(SearchQuery { name: "img_000".to_string(), ..Default::default() }).1,
+            ["DCIM/IMG_0003.JPG", "DCIM/IMG_0002.jpg", "IMG_0001.jpg"]);
+        assert_eq!(search(SearchQuery { name: "IMG_*.jpg".to_string(), ..Default::default() }).1,
+            ["DCIM/IMG_0002.jpg", "IMG_0001.jpg"]);
+    }
+
+    #[test]
+    fn extensions_ignore_case_and_dots() {
+        assert_eq!(search(SearchQuery { extension: Some(".JPG".to_string()), ..Default::default() }).0, 3);
+        assert_eq!(search(SearchQuery { extension: Some("mp4".to_string()), ..Default::default() }).1, ["clip.mp4"]);
+    }
+
+    #[test]
+    fn size_date_and_camera_filters() {
+        assert_eq!(search(SearchQuery { min_size: Some(5_000), max_size: Some(9_000), ..Default::default() }).1,
+            ["DCIM/IMG_0003.JPG", "DCIM/IMG_0002.jpg"]);
+        assert_eq!(search(SearchQuery { captured_after: Some(200), captured_before: Some(400), ..Default::default() }).1,
+            ["DCIM/IMG_0002.jpg", "clip.mp4"]);
+        assert_eq!(search(SearchQuery { camera: Some("pixel".to_string()), ..Default::default() }).1,
+            ["clip.mp4", "IMG_0001.jpg"]);
+    }
+
+    #[test]
+    fn pages() {
+        let (total, first) = search(SearchQuery { page_size: 3, ..Default::default() });
+        assert_eq!(total, 4);
+        assert_eq!(first, ["DCIM/IMG_0003.JPG", "DCIM/IMG_0002.jpg", "clip.mp4"]);
+        let (total, second) = search(SearchQuery { page: 1, page_size: 3, ..Default::default() });
+        assert_eq!(total, 4);
+        assert_eq!(second, ["IMG_0001.jpg"]);
+        assert!(search(SearchQuery { page: 2, page_size: 3, ..Default::default() }).1.is_empty());
+    }
+
+    #[test]
+    fn page_sizes_are_capped() {
+        let results = catalog().search(&SearchQuery { page_size: 10_000, ..Default::default() }, &["photos".to_string()]).unwrap();
+        assert_eq!(results.page_size, MAX_PAGE_SIZE);
+        let results = catalog().search(&SearchQuery::default(), &["photos".to_string()]).unwrap();
+        assert_eq!(results.page_size, DEFAULT_PAGE_SIZE);
+    }
+}
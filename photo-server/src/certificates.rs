@@ -0,0 +1,24 @@
+use shared::{auth::write_private_file, tls::{load_server_config, ServerTlsConfig}};
+use crate::request_handler::request_handler_utils::ServerConfig;
+
+// loads the certificate named in the config, or generates a self signed one the first time tls is enabled
+pub fn load_or_generate(config:&mut ServerConfig, hostname:&str) -> anyhow::Result<ServerTlsConfig> {
+    if config.certificate_path.is_empty() {
+        config.certificate_path = "photo-server-cert.pem".to_string();
+    }
+    if config.private_key_path.is_empty() {
+        config.private_key_path = "photo-server-key.pem".to_string();
+    }
+
+    let certificate_path = std::path::Path::new(&config.certificate_path);
+    let private_key_path = std::path::Path::new(&config.private_key_path);
+    if !certificate_path.exists() || !private_key_path.exists() {
+        println!("Generating a self signed certificate for {}", hostname);
+        let certified_key = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+        std::fs::write(certificate_path, certified_key.cert.pem())?;
+        write_private_file(&config.private_key_path, certified_key.key_pair.serialize_pem().as_bytes())?;
+    }
+
+    // the paths are saved along with the rest of the config once the server has set it up
+    load_server_config(&config.certificate_path, &config.private_key_path)
+}
@@ -1,12 +1,80 @@
 use std::{
-    collections::HashMap,io::prelude::*, net::{TcpListener, TcpStream}, path::PathBuf, sync::{Arc, atomic, mpsc}, thread::JoinHandle
+    collections::HashMap,io::prelude::*, net::{TcpListener, TcpStream}, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic, mpsc}, thread::JoinHandle, time::{Duration, Instant, SystemTime}
 };
-use shared::{send_response, Response, Tree, FileHeader, Job};
+use shared::{tree::sanitize_relative_path, read_file_header, read_message, read_upload_chunk, send_response, tls::{self, ServerTlsConfig}, Codec, DataSessionHello, FileEntry, FileHeader, FileResult, RepoQuota, Response, Role, Stream, TreeChange, TreeOperation, Job};
+use crate::catalog::{Catalog, FileRecord};
+use crate::server::Stores;
+use crate::object_store::ObjectStore;
+use crate::tree_store::TreeStore;
+use crate::request_handler::request_handler_utils::ServerConfig;
 
-pub fn initiate_batch_processor(storage_directory: PathBuf, listener:TcpListener, stop_flag:Arc<atomic::AtomicBool>) -> anyhow::Result<JoinHandle<()>>{   
-    match listener.accept() {
-        Ok((mut file_stream, _)) => {
-            
+// batch processors waiting for their client, keyed by the session token handed out with the offer
+pub type SessionRegistry = Arc<Mutex<HashMap<String, mpsc::Sender<Stream>>>>;
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+// a client that stops sending in the middle of a batch is treated as gone, it reconnects with its session token to resume
+const DATA_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// every file stream comes in on the one data port and is handed to the batch processor that issued its token
+pub fn run_data_listener(port:u16, sessions:SessionRegistry, tls_config:Option<ServerTlsConfig>) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    println!("File streams accepted on port {}", port);
+
+    Ok(std::thread::spawn(move || {
+        for file_stream in listener.incoming() {
+            let file_stream = match file_stream {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+            let sessions = sessions.clone();
+            let tls_config = tls_config.clone();
+
+            // the handshake happens off the listener thread so a slow client can't hold up the others
+            std::thread::spawn(move || {
+                if let Err(e) = route_file_stream(file_stream, &sessions, &tls_config) {
+                    println!("file stream rejected. {}", e);
+                }
+            });
+        }
+    }))
+}
+
+fn route_file_stream(file_stream:TcpStream, sessions:&SessionRegistry, tls_config:&Option<ServerTlsConfig>) -> anyhow::Result<()> {
+    file_stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let mut file_stream = wrap_stream(file_stream, tls_config)?;
+    let hello: DataSessionHello = read_message(&mut file_stream)?;
+
+    let session = sessions.lock()
+        .map_err(|e| anyhow::anyhow!("session registry lock poisoned: {}", e))?
+        .get(&hello.session_token)
+        .cloned();
+
+    match session {
+        Some(session) => {
+            file_stream.set_read_timeout(Some(DATA_READ_TIMEOUT))?;
+            session.send(file_stream).map_err(|_| anyhow::anyhow!("the batch processor for this session has stopped"))?;
+            Ok(())
+        }
+        None => {
+            let response = Response {
+                status_code:shared::ResponseCodes::Unauthorized,
+                status_message:"Unauthorized".to_string(),
+                body: "Unknown file stream session".as_bytes().to_vec(),
+            };
+            send_response(response, &mut file_stream)?;
+            Err(anyhow::anyhow!("{} presented an unknown session token", file_stream.peer_addr()?))
+        }
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn initiate_batch_processor(config: ServerConfig, file_streams:mpsc::Receiver<Stream>, stop_flag:Arc<atomic::AtomicBool>, stores:Stores, codecs:Vec<Codec>, user:String, device:String) -> anyhow::Result<JoinHandle<()>>{   
+    match file_streams.recv_timeout(CONNECT_TIMEOUT) {
+        Ok(mut file_stream) => {
             let response: Response = Response {
                 status_code:shared::ResponseCodes::OK,
                 status_message:"OK".to_string(),
@@ -18,45 +86,169 @@ pub fn initiate_batch_processor(storage_directory: PathBuf, listener:TcpListener
             return Ok(std::thread::spawn(move || {
                 println!("file stream thread initiated");
                 
-                let mut file_stream_server = BatchProcessor::new(storage_directory, file_stream, stop_flag);
-                match file_stream_server.listen() {
-                    Ok(_) => {} // handle result
-                    Err(e) => println!("{}",e)
-                };
+                loop {
+                    let mut file_stream_server = BatchProcessor::new(&config, file_stream, stop_flag.clone(), stores.clone(), codecs.clone(), user.clone(), device.clone());
+                    match file_stream_server.listen() {
+                        Ok(_) => {} // handle result
+                        Err(e) => println!("{}",e)
+                    };
+
+                    // the client reconnects with the same session token to resume an interrupted upload
+                    file_stream = match accept_reconnect(&file_streams, &stop_flag) {
+                        Some(s) => s,
+                        None => break,
+                    };
+                }
             }));
         },
-        Err(e) => return Err(anyhow::anyhow!(e)),
+        Err(e) => return Err(anyhow::anyhow!("client never opened the file stream. {}", e)),
+    }
+}
+
+fn wrap_stream(file_stream:TcpStream, tls_config:&Option<ServerTlsConfig>) -> anyhow::Result<Stream> {
+    match tls_config {
+        Some(tls_config) => tls::accept(file_stream, tls_config.clone()),
+        None => Ok(Stream::Plain(file_stream)),
+    }
+}
+
+const RECONNECT_WINDOW: Duration = Duration::from_secs(300);
+
+fn accept_reconnect(file_streams:&mpsc::Receiver<Stream>, stop_flag:&Arc<atomic::AtomicBool>) -> Option<Stream> {
+    let deadline = Instant::now() + RECONNECT_WINDOW;
+
+    while !stop_flag.load(atomic::Ordering::Relaxed) && Instant::now() < deadline {
+        match file_streams.recv_timeout(Duration::from_millis(100)) {
+            Ok(mut file_stream) => {
+                let response = Response {
+                    status_code:shared::ResponseCodes::OK,
+                    status_message:"OK".to_string(),
+                    body: "Resumed batch processor".as_bytes().to_vec(),
+                };
+                send_response(response, &mut file_stream).ok()?;
+                println!("file stream reconnected");
+                return Some(file_stream);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+    None
+}
+
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+}
+
+// a .part file nobody has written to for this long belongs to an upload the client gave up on
+const PART_FILE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// removes abandoned uploads from storage/.partial, returning how many were removed
+fn remove_stale_part_files(partial_directory: &Path) -> std::io::Result<usize> {
+    let entries = match std::fs::read_dir(partial_directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let modified = entry.metadata()?.modified()?;
+        if modified.elapsed().is_ok_and(|age| age > PART_FILE_EXPIRY) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
     }
+    Ok(removed)
+}
+
+// where a file with the given relative path ends up inside the repository storage
+pub fn stored_file_path(storage_directory: &Path, repo_name: &str, relative_path: &str) -> Option<PathBuf> {
+    let relative_path = sanitize_relative_path(relative_path)?;
+    Some(storage_directory.join(repo_name).join(relative_path))
 }
 
+// why storing size bytes at relative_path would take the upload's repository over quota, None when it fits
+fn quota_exceeded(tree_store:&TreeStore, quota:RepoQuota, file_header:&FileHeader, size:u64, relative_path:&str) -> Option<String> {
+    if quota == RepoQuota::default() {
+        return None;
+    }
+    let (file_count, bytes, replaced) = match tree_store.get(&file_header.repo_name) {
+        Some(tree) => (
+            tree.files.len() as u64,
+            tree.files.values().map(|entry| entry.size).sum::<u64>(),
+            tree.files.get(relative_path).map(|entry| entry.size),
+        ),
+        None => (0, 0, None),
+    };
+
+    // an upload over a tracked path replaces that file instead of adding one
+    let new_file_count = if replaced.is_some() { file_count } else { file_count + 1 };
+    let new_bytes = (bytes + size).saturating_sub(replaced.unwrap_or(0));
+
+    // a repository that is already over a lowered quota can still shrink
+    if let Some(max_files) = quota.max_files.filter(|max_files| new_file_count > *max_files && new_file_count > file_count) {
+        return Some(format!("{} already holds {} of its {} files", file_header.repo_name, file_count, max_files));
+    }
+    if let Some(max_bytes) = quota.max_bytes.filter(|max_bytes| new_bytes > *max_bytes && new_bytes > bytes) {
+        return Some(format!("{} would take {} to {} bytes, over its {} byte quota", file_header.file_name, file_header.repo_name, new_bytes, max_bytes));
+    }
+    None
+}
 
 struct BatchProcessor {
     storage_directory: PathBuf,
-    stream:TcpStream,
+    stream:Stream,
     stop_flag: std::sync::Arc<std::sync::atomic::AtomicBool>,
-    trees:HashMap<String, Tree>,
+    stores: Stores,
+    codecs: Vec<Codec>, // negotiated when the batch processor was started
+    user: String, // uploads are only stored in repositories this user can write to
+    device: String, // recorded in the catalog as the uploader
+    raw_bytes: u64,
+    wire_bytes: u64,
 }
 
 impl BatchProcessor {
-    pub fn new(storage_directory: PathBuf, stream:TcpStream, stop_flag:std::sync::Arc<std::sync::atomic::AtomicBool>) -> Self{
+    pub fn new(config:&ServerConfig, stream:Stream, stop_flag:std::sync::Arc<std::sync::atomic::AtomicBool>, stores:Stores, codecs:Vec<Codec>, user:String, device:String) -> Self{
         BatchProcessor {
-            storage_directory,
+            storage_directory: PathBuf::from(&config.storage_directory),
             stream,
             stop_flag,
-            trees: HashMap::new(),
+            stores,
+            codecs,
+            user,
+            device,
+            raw_bytes: 0,
+            wire_bytes: 0,
         }
     }
 
     pub fn listen(&mut self) -> anyhow::Result<()> {
-
+        match remove_stale_part_files(&self.storage_directory.join(".partial")) {
+            Ok(0) => {}
+            Ok(removed) => println!("Removed {} abandoned partial uploads", removed),
+            Err(e) => println!("Unable to remove abandoned partial uploads. {}", e),
+        }
 
         while !self.stop_flag.load(std::sync::atomic::Ordering::Relaxed) {
             match self.process_batch_job() {
-                Ok(_) => {
+                Ok(None) => break,
+                Ok(Some(results)) => {
+                    let saved_bytes = self.raw_bytes.saturating_sub(self.wire_bytes);
+                    let stored = results.iter().filter(|r| r.status_code == shared::ResponseCodes::OK).count();
+
+                    // the overall code is the first failure so a glance at the log still tells something went wrong
+                    let status_code = results.iter()
+                        .map(|r| r.status_code)
+                        .find(|code| *code != shared::ResponseCodes::OK)
+                        .unwrap_or(shared::ResponseCodes::OK);
+
                     let response = Response {
-                        status_code:shared::ResponseCodes::OK,
-                        status_message: "OK".to_string(),
-                        body: format!("processed batch job").as_bytes().to_vec(),
+                        status_code,
+                        status_message: format!("stored {} of {} files | received {} bytes for {} bytes of files, {} saved by compression",
+                            stored, results.len(), self.wire_bytes, self.raw_bytes, saved_bytes),
+                        body: serde_json::to_vec(&results)?,
                     };
                     
                     if let Err(e) = send_response(response, &mut self.stream) {
@@ -68,6 +260,8 @@ impl BatchProcessor {
                     if e.to_string().contains("UnexpectedEnd") || 
                     e.to_string().contains("EOF") {
                         println!("Connection closed by client");
+                    } else if is_timeout(&e) {
+                        println!("File stream timed out, waiting for the client to resume");
                     } else {
                         println!("Connection error: {}", e);
                     }
@@ -78,64 +272,417 @@ impl BatchProcessor {
         Ok(())
     }
 
-    fn process_batch_job(&mut self) -> anyhow::Result<()> {
-        let mut batch_header_length_buffer = [0u8; 4];
-        
-        self.stream.read_exact(&mut batch_header_length_buffer)?;
+    fn open_part_file(&self, file_header:&FileHeader) -> anyhow::Result<(PathBuf, std::fs::File, u64)> {
+        if file_header.upload_id.is_empty() || !file_header.upload_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!("invalid upload id for {}", file_header.file_name));
+        }
+        if file_header.codec != Codec::None && !self.codecs.contains(&file_header.codec) {
+            return Err(anyhow::anyhow!("{} uses codec {} which was not negotiated", file_header.file_name, file_header.codec));
+        }
 
-        let batch_num_jobs: u32 = u32::from_be_bytes(batch_header_length_buffer);
-        let mut jobs = Vec::<Job>::new();
+        let partial_directory = self.storage_directory.join(".partial");
+        std::fs::create_dir_all(&partial_directory)?;
+        let part_path = partial_directory.join(format!("{}.part", file_header.upload_id));
 
-        for i in 0..batch_num_jobs {
-            let mut header_size_buf = [0u8; 4];
-            self.stream.read_exact(&mut header_size_buf)?;
-            let header_size = u32::from_be_bytes(header_size_buf) as usize;
+        let mut committed = std::fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+        if committed > file_header.file_size as u64 {
+            committed = 0;
+        }
+        let mut part_file = std::fs::OpenOptions::new().create(true).write(true).truncate(false).open(&part_path)?;
+        part_file.set_len(committed)?;
+        part_file.seek(std::io::SeekFrom::Start(committed))?;
+        Ok((part_path, part_file, committed))
+    }
 
-            let mut header_bytes = vec![0u8; header_size];
-            self.stream.read_exact(&mut header_bytes)?;
-            let (file_header, _):(FileHeader, usize) = bincode::decode_from_slice(&header_bytes, bincode::config::standard())?;
+    // reads an upload off the stream without keeping any of it, so the next file starts where the client thinks it does
+    fn discard_upload(&mut self) -> anyhow::Result<()> {
+        self.stream.write_all(&0u64.to_be_bytes())?;
+        while read_upload_chunk(&mut self.stream)?.is_some() {}
+        Ok(())
+    }
 
-            let mut job_data_bytes = vec![0u8,file_header.file_size as u8];
+    // why the upload can't be stored, decided from its header before any of it is written to disk
+    // the quota is checked again under the tree store lock once the upload is complete
+    fn reject_upload(&self, file_header:&FileHeader, config:&ServerConfig) -> anyhow::Result<Option<FileResult>> {
+        let rejection = |status_code, message:String| Ok(Some(FileResult::failed(&file_header.file_location, status_code, message)));
+        if !config.repo_list.contains(&file_header.repo_name) {
+            return rejection(shared::ResponseCodes::NotFound, format!("{} does not exist", file_header.repo_name));
+        }
+        if !matches!(config.role(&file_header.repo_name, &self.user), Some(role) if role >= Role::Writer) {
+            return rejection(shared::ResponseCodes::Unauthorized, format!("{} can't write to {}", self.user, file_header.repo_name));
+        }
+        let Some(relative_path) = sanitize_relative_path(&file_header.relative_path) else {
+            return rejection(shared::ResponseCodes::InternalError, format!("{} is not a valid path inside the repository", file_header.relative_path));
+        };
 
-                
-            loop {
-                let mut chunk_size_buf = [0u8; 4];
-                self.stream.read_exact(&mut chunk_size_buf)?;
-                let chunk_size = u32::from_be_bytes(chunk_size_buf) as usize;
-                if chunk_size == 0 { break; }
-
-                let mut chunk = vec![0u8; chunk_size];
-                self.stream.read_exact(&mut chunk)?;
-                job_data_bytes.extend_from_slice(&chunk);
+        let tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+        match quota_exceeded(&tree_store, config.quota(&file_header.repo_name), file_header, file_header.file_size as u64, &relative_path) {
+            Some(message) => rejection(shared::ResponseCodes::QuotaExceeded, message),
+            None => Ok(None),
+        }
+    }
+
+    // appends the upload to its .part file so a dropped connection only loses the chunk in flight
+    // problems with the file itself come back as a failed result, only stream errors end the batch
+    fn receive_upload(&mut self, config:&ServerConfig) -> anyhow::Result<Result<Job, FileResult>> {
+        let file_header = read_file_header(&mut self.stream)?;
+
+        if let Some(rejection) = self.reject_upload(&file_header, config)? {
+            self.discard_upload()?;
+            return Ok(Err(rejection));
+        }
+        let (part_path, mut part_file, mut committed) = match self.open_part_file(&file_header) {
+            Ok(part) => part,
+            Err(e) => {
+                self.discard_upload()?;
+                return Ok(Err(FileResult::failed(&file_header.file_location, shared::ResponseCodes::InternalError, e.to_string())));
             }
+        };
 
-            jobs.push(Job {file_header: file_header, data: job_data_bytes});
+        if committed > 0 {
+            println!("Resuming {} at {} of {} bytes", file_header.file_name, committed, file_header.file_size);
         }
+        self.stream.write_all(&committed.to_be_bytes())?;
 
-        for job in jobs {
-            let file_header = job.file_header;
-            let file_path = self.storage_directory.join(&file_header.repo_name).join(file_header.file_name);
+        let mut failure: Option<String> = None;
+        while let Some((offset, wire_chunk)) = read_upload_chunk(&mut self.stream)? {
+            if failure.is_some() {
+                continue;
+            }
+            if offset != committed {
+                return Err(anyhow::anyhow!("{} chunk starts at {} but {} bytes are committed", file_header.file_name, offset, committed));
+            }
 
-            if !self.trees.contains_key(&file_header.repo_name) {
-                let tree_path = PathBuf::from("trees").join(format!("{}.tree", &file_header.repo_name));
-                let tree = Tree::load_from_file(tree_path.to_string_lossy().as_ref());
-                self.trees.insert(file_header.repo_name.clone(), tree);
+            let written = file_header.codec.decompress(&wire_chunk).and_then(|chunk| {
+                // the header's size is what the role and quota were checked against
+                if committed + chunk.len() as u64 > file_header.file_size as u64 {
+                    return Err(anyhow::anyhow!("more data than the {} bytes it announced", file_header.file_size));
+                }
+                part_file.write_all(&chunk)?;
+                part_file.sync_data()?;
+                Ok(chunk.len() as u64)
+            });
+            match written {
+                Ok(chunk_size) => {
+                    self.wire_bytes += wire_chunk.len() as u64;
+                    self.raw_bytes += chunk_size;
+                    committed += chunk_size;
+                }
+                Err(e) => failure = Some(format!("{} could not be written | {}", file_header.file_name, e)),
             }
-            
-            if let Some(tree) = self.trees.get_mut(&file_header.repo_name) {
-                let start_index = tree.add_history( format!("+{}", file_header.file_location.to_string()));
-                tree.apply_history(start_index);
-                tree.save_to_file(&tree.path);
+        }
+        drop(part_file);
+
+        if let Some(failure) = failure {
+            return Ok(Err(FileResult::failed(&file_header.file_location, shared::ResponseCodes::InternalError, failure)));
+        }
+
+        let job = Job {
+            data: std::fs::read(&part_path)?,
+            file_header,
+        };
+        std::fs::remove_file(&part_path)?;
+        match job.verify() {
+            Ok(_) => Ok(Ok(job)),
+            Err(e) => Ok(Err(FileResult::failed(&job.file_header.file_location, shared::ResponseCodes::IntegrityError, e))),
+        }
+    }
+
+    // stores one verified upload and records it in the tree, returning the version it was given
+    // the quota is checked under the same tree store lock the upload is recorded under, so batch processors storing into one repository can't overshoot it together
+    fn store_job(&mut self, job:&Job, quota:RepoQuota) -> anyhow::Result<FileResult> {
+        let file_header = &job.file_header;
+        let relative_path = sanitize_relative_path(&file_header.relative_path)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a valid path inside the repository", file_header.relative_path))?;
+        let file_path = self.storage_directory.join(&file_header.repo_name).join(&relative_path);
+
+        // the blob write and the metadata parse are the slow part, neither needs the tree store
+        let staged_path = ObjectStore::stage_blob(&self.storage_directory, &file_header.file_hash, &job.data)?;
+        let entry = FileEntry {
+            size: job.data.len() as u64,
+            hash: file_header.file_hash.clone(),
+            created: file_header.file_datetime,
+            uploaded: SystemTime::now(),
+            file_location: file_header.file_location.clone(),
+        };
+        let record = FileRecord::new(&file_header.repo_name, &relative_path, &entry, &staged_path, &self.device);
+
+        // the tree store is always locked before the object store
+        let mut tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+        if let Some(message) = quota_exceeded(&tree_store, quota, file_header, job.data.len() as u64, &relative_path) {
+            println!("Rejecting {}: {}", file_header.file_name, message);
+            std::fs::remove_file(&staged_path).ok();
+            return Ok(FileResult::failed(&file_header.file_location, shared::ResponseCodes::QuotaExceeded, message));
+        }
+        println!("Receiving file: {} ({} bytes)", file_path.to_string_lossy().into_owned(), file_header.file_size);
+
+        let deduplicated = self.stores.object_store.lock()
+            .map_err(|e| anyhow::anyhow!("object store lock poisoned: {}", e))?
+            .store(&self.storage_directory, &file_path, &file_header.file_hash, &staged_path)?;
+        if deduplicated {
+            println!("{} is already stored, linked to the existing copy", file_header.file_name);
+        }
+
+        // an upload over a tracked path is a modification, unless the contents didn't change at all
+        let operation = match tree_store.tree_mut(&file_header.repo_name).files.get(&relative_path) {
+            Some(existing) if existing.hash == entry.hash => None,
+            Some(_) => Some(TreeOperation::Modify { path: relative_path, entry }),
+            None => Some(TreeOperation::Add { path: relative_path, entry }),
+        };
+        let tree_version = operation.map(|operation| tree_store.record(&file_header.repo_name, operation)).transpose()?;
+        if tree_version.is_some() {
+            self.catalog(|catalog| catalog.record_file(&record));
+            if record.media_type == "image" {
+                self.stores.thumbnails.queue(&file_header.file_hash, &file_path);
             }
+        }
 
-            println!("Receiving file: {} ({} bytes)", file_path.to_string_lossy().into_owned(), file_header.file_size);
+        Ok(FileResult {
+            file_location: file_header.file_location.clone(),
+            status_code: shared::ResponseCodes::OK,
+            stored_path: file_path.to_string_lossy().to_string(),
+            bytes_written: if deduplicated { 0 } else { job.data.len() as u64 },
+            tree_version,
+            message: if deduplicated { "linked to an existing copy".to_string() } else { "stored".to_string() },
+        })
+    }
 
-            if let Some(parent) = file_path.parent() {
-                std::fs::create_dir_all(parent)?;
+    // the catalog can be rebuilt from the trees, so failing to update it is logged rather than failing the upload
+    fn catalog<F: FnOnce(&mut Catalog) -> rusqlite::Result<()>>(&self, update: F) {
+        let result = match self.stores.catalog.lock() {
+            Ok(mut catalog) => update(&mut catalog).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("catalog lock poisoned: {}", e)),
+        };
+        if let Err(e) = result {
+            println!("Failed to update the catalog, it will be rebuilt on the next start. {}", e);
+        }
+    }
+
+    // applies a removal or rename the client saw in its watch directory to the stored files and the tree
+    fn apply_change(&mut self, change:TreeChange) -> anyhow::Result<FileResult> {
+        let repo_path = self.storage_directory.join(&change.repo_name);
+        // the tree store is always locked before the object store
+        let mut tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+        let mut object_store = self.stores.object_store.lock()
+            .map_err(|e| anyhow::anyhow!("object store lock poisoned: {}", e))?;
+        let tree = tree_store.tree_mut(&change.repo_name);
+
+        let invalid_path = |path:&str| anyhow::anyhow!("{} is not a valid path inside the repository", path);
+        let (path, operation) = match change.operation {
+            TreeOperation::Remove { path } => {
+                let path = sanitize_relative_path(&path).ok_or_else(|| invalid_path(&path))?;
+                if !tree.contains(&path) {
+                    // deleting a directory reports its files first, by then there's nothing left to drop
+                    return Ok(FileResult {
+                        file_location: path,
+                        status_code: shared::ResponseCodes::OK,
+                        stored_path: String::new(),
+                        bytes_written: 0,
+                        tree_version: None,
+                        message: "not tracked".to_string(),
+                    });
+                }
+                for removed_path in tree.files_in_directory(&path).into_iter().chain([path.clone()]) {
+                    if tree.files.contains_key(&removed_path) {
+                        object_store.remove(&repo_path.join(&removed_path))?;
+                    }
+                }
+                self.catalog(|catalog| catalog.remove_path(&change.repo_name, &path).map(|_| ()));
+                (path.clone(), TreeOperation::Remove { path })
+            }
+            TreeOperation::Rename { from, to } => {
+                let from = sanitize_relative_path(&from).ok_or_else(|| invalid_path(&from))?;
+                let to = sanitize_relative_path(&to).ok_or_else(|| invalid_path(&to))?;
+                if !tree.contains(&from) {
+                    return Ok(FileResult::failed(&from, shared::ResponseCodes::NotFound, format!("{} is not tracked", from)));
+                }
+                if to.starts_with(&format!("{}/", from)) {
+                    return Ok(FileResult::failed(&from, shared::ResponseCodes::InternalError, format!("{} can't be moved inside itself", from)));
+                }
+                let renamed_paths = tree.renamed_paths(&from, &to);
+                for (old_path, new_path) in &renamed_paths {
+                    object_store.rename(&repo_path.join(old_path), &repo_path.join(new_path))?;
+                }
+                self.catalog(|catalog| catalog.rename_paths(&change.repo_name, &renamed_paths));
+                (to.clone(), TreeOperation::Rename { from, to })
             }
-            std::fs::write(&file_path, &job.data)?;
+            _ => return Err(anyhow::anyhow!("clients can only remove or rename entries, uploads add them")),
+        };
 
-        } 
-        Ok(())
+        let tree_version = tree_store.record(&change.repo_name, operation)?;
+        Ok(FileResult {
+            file_location: path.clone(),
+            status_code: shared::ResponseCodes::OK,
+            stored_path: repo_path.join(&path).to_string_lossy().to_string(),
+            bytes_written: 0,
+            tree_version: Some(tree_version),
+            message: "updated".to_string(),
+        })
     }
-}
\ No newline at end of file
+
+    // waits as long as it takes for the next batch, the client only sends one once something changed
+    // the read timeout applies again as soon as the batch starts, None once the batch processor is stopped
+    fn wait_for_batch(&mut self) -> anyhow::Result<Option<u32>> {
+        let mut batch_header_length_buffer = [0u8; 4];
+        let mut filled = 0;
+        while filled < batch_header_length_buffer.len() {
+            match self.stream.read(&mut batch_header_length_buffer[filled..]) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(read) => filled += read,
+                Err(e) if filled == 0 && matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    if self.stop_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Some(u32::from_be_bytes(batch_header_length_buffer)))
+    }
+
+    // returns a result for every file in the batch, in the order the client sent them
+    fn process_batch_job(&mut self) -> anyhow::Result<Option<Vec<FileResult>>> {
+        let Some(batch_num_jobs) = self.wait_for_batch()? else {
+            return Ok(None);
+        };
+        self.raw_bytes = 0;
+        self.wire_bytes = 0;
+        let mut uploads = Vec::<Result<Job, FileResult>>::new();
+
+        // copied per batch so roles changed while the batch processor is open take effect
+        let config = self.stores.config.lock()
+            .map_err(|e| anyhow::anyhow!("config lock poisoned: {}", e))?
+            .clone();
+
+        for _ in 0..batch_num_jobs {
+            let upload = self.receive_upload(&config)?;
+            if let Err(result) = &upload {
+                println!("Rejecting file: {}", result.message);
+            }
+            uploads.push(upload);
+        }
+        let changes: Vec<TreeChange> = read_message(&mut self.stream)?;
+
+        let mut results = Vec::<FileResult>::new();
+
+        for upload in uploads {
+            let job = match upload {
+                Ok(job) => job,
+                Err(result) => {
+                    results.push(result);
+                    continue;
+                }
+            };
+
+            // the role was checked before the upload was received
+            let file_header = &job.file_header;
+            match self.store_job(&job, config.quota(&file_header.repo_name)) {
+                Ok(result) => results.push(result),
+                Err(e) => {
+                    println!("Failed to store {}: {}", file_header.file_name, e);
+                    results.push(FileResult::failed(&file_header.file_location, shared::ResponseCodes::InternalError, e.to_string()));
+                }
+            }
+        }
+
+        // changes come after the uploads so a file added and renamed in the same batch ends up renamed
+        for change in changes {
+            if !matches!(config.role(&change.repo_name, &self.user), Some(role) if role >= Role::Writer) {
+                results.push(FileResult::failed(&change.repo_name, shared::ResponseCodes::Unauthorized,
+                    format!("{} can't write to {}", self.user, change.repo_name)));
+                continue;
+            }
+            let repo_name = change.repo_name.clone();
+            match self.apply_change(change) {
+                Ok(result) => results.push(result),
+                Err(e) => {
+                    println!("Failed to update {}: {}", repo_name, e);
+                    results.push(FileResult::failed(&repo_name, shared::ResponseCodes::InternalError, e.to_string()));
+                }
+            }
+        }
+        Ok(Some(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(relative_path: &str) -> FileHeader {
+        FileHeader {
+            repo_name: "photos".to_string(),
+            file_name: relative_path.to_string(),
+            file_size: 0,
+            file_location: relative_path.to_string(),
+            relative_path: relative_path.to_string(),
+            file_ext: "jpg".to_string(),
+            file_datetime: SystemTime::UNIX_EPOCH,
+            file_hash: String::new(),
+            upload_id: String::new(),
+            codec: Codec::default(),
+        }
+    }
+
+    // a repository holding a.jpg and b.jpg, 100 bytes each
+    fn tree_store() -> TreeStore {
+        let mut tree_store = TreeStore::default();
+        for path in ["a.jpg", "b.jpg"] {
+            tree_store.tree_mut("photos").files.insert(path.to_string(), FileEntry {
+                size: 100,
+                hash: path.to_string(),
+                created: SystemTime::UNIX_EPOCH,
+                uploaded: SystemTime::UNIX_EPOCH,
+                file_location: path.to_string(),
+            });
+        }
+        tree_store
+    }
+
+    fn quota(max_bytes: Option<u64>, max_files: Option<u64>) -> RepoQuota {
+        RepoQuota { max_bytes, max_files }
+    }
+
+    #[test]
+    fn no_quota_accepts_anything() {
+        assert_eq!(quota_exceeded(&tree_store(), RepoQuota::default(), &header("c.jpg"), u64::MAX / 2, "c.jpg"), None);
+    }
+
+    #[test]
+    fn file_limit() {
+        let tree_store = tree_store();
+        assert_eq!(quota_exceeded(&tree_store, quota(None, Some(3)), &header("c.jpg"), 100, "c.jpg"), None);
+        assert!(quota_exceeded(&tree_store, quota(None, Some(2)), &header("c.jpg"), 100, "c.jpg").is_some());
+        // replacing a tracked file doesn't add one
+        assert_eq!(quota_exceeded(&tree_store, quota(None, Some(2)), &header("a.jpg"), 100, "a.jpg"), None);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let tree_store = tree_store();
+        assert_eq!(quota_exceeded(&tree_store, quota(Some(300), None), &header("c.jpg"), 100, "c.jpg"), None);
+        assert!(quota_exceeded(&tree_store, quota(Some(300), None), &header("c.jpg"), 101, "c.jpg").is_some());
+        // only the difference counts when a tracked file is replaced
+        assert_eq!(quota_exceeded(&tree_store, quota(Some(250), None), &header("a.jpg"), 150, "a.jpg"), None);
+        assert!(quota_exceeded(&tree_store, quota(Some(250), None), &header("a.jpg"), 151, "a.jpg").is_some());
+    }
+
+    #[test]
+    fn over_a_lowered_quota_can_still_shrink() {
+        let tree_store = tree_store();
+        assert_eq!(quota_exceeded(&tree_store, quota(Some(50), Some(1)), &header("a.jpg"), 10, "a.jpg"), None);
+        assert!(quota_exceeded(&tree_store, quota(Some(50), Some(1)), &header("a.jpg"), 200, "a.jpg").is_some());
+        assert!(quota_exceeded(&tree_store, quota(Some(50), Some(1)), &header("c.jpg"), 0, "c.jpg").is_some());
+    }
+
+    #[test]
+    fn an_untracked_repository_starts_empty() {
+        let mut header = header("a.jpg");
+        header.repo_name = "new".to_string();
+        assert_eq!(quota_exceeded(&tree_store(), quota(Some(100), Some(1)), &header, 100, "a.jpg"), None);
+        assert!(quota_exceeded(&tree_store(), quota(Some(99), Some(1)), &header, 100, "a.jpg").is_some());
+    }
+}
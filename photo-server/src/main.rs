@@ -1,9 +1,22 @@
-use std::{env, path::PathBuf};
+use std::{collections::HashSet, env, path::{Path, PathBuf}, sync::Arc};
 use hostname::get;
-use server::PhotoServer;
+use server::{PhotoServer, Stores};
 use request_handler::request_handler_utils::ServerConfig;
+use object_store::ObjectStore;
+use tree_store::TreeStore;
+use catalog::Catalog;
+use thumbnails::Thumbnails;
+use shared::auth::{new_nonce, Identity};
 mod server;
 mod filestreamserver;
+mod object_store;
+mod tree_store;
+mod catalog;
+mod media_metadata;
+mod thumbnails;
+mod certificates;
+mod authentication;
+mod tree_migration;
 
 mod request_handler;
 
@@ -23,14 +36,121 @@ fn main() {
         }
     }
 
-    let mut config = ServerConfig::load_from_file(config_name);
+    let mut config = match ServerConfig::load_from_file(config_name) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Unable to load the config, fix or restore it before starting the server. {}", e);
+            return;
+        }
+    };
     config.config_path = config_name.to_string();
-    config.save_to_file(&config.config_path);
+
+    let object_store = match ObjectStore::load_from_file("photo-server-objects.json") {
+        Ok(object_store) => object_store,
+        Err(e) => {
+            println!("Unable to load the object store index, fix or restore it before starting the server. {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = tree_migration::migrate_trees(Path::new(&config.storage_directory)) {
+        println!("Unable to migrate the repository trees. {}", e);
+    }
+    if let Err(e) = tree_migration::recover_trees() {
+        println!("Unable to recover the repository trees, fix or restore the tree before starting the server. {}", e);
+        return;
+    }
+    let tree_store = match TreeStore::load("trees") {
+        Ok(tree_store) => tree_store,
+        Err(e) => {
+            println!("Unable to load the repository trees. {}", e);
+            return;
+        }
+    };
+
+    let mut catalog = match Catalog::open("photo-server-catalog.db") {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            println!("Unable to open the file catalog. {}", e);
+            return;
+        }
+    };
+    // a new, deleted or out of date catalog is rebuilt from the trees
+    if catalog.file_count().ok() != Some(tree_store.file_count()) {
+        match catalog.rebuild(&tree_store, Path::new(&config.storage_directory)) {
+            Ok(file_count) => println!("Rebuilt the file catalog with {} files", file_count),
+            Err(e) => println!("Unable to rebuild the file catalog. {}", e),
+        }
+    }
+
+    let thumbnails = match Thumbnails::start("photo-server-thumbnails") {
+        Ok(thumbnails) => thumbnails,
+        Err(e) => {
+            println!("Unable to start the thumbnail worker. {}", e);
+            return;
+        }
+    };
+    // images stored before thumbnails existed, or while the server was down, are caught up in the background
+    let mut hashes = HashSet::<String>::new();
+    for (repo_name, tree) in tree_store.trees() {
+        for (relative_path, entry) in &tree.files {
+            if shared::media_type(relative_path) == "image" {
+                thumbnails.queue(&entry.hash, &Path::new(&config.storage_directory).join(repo_name).join(relative_path));
+            }
+            hashes.insert(entry.hash.clone());
+        }
+    }
+    match thumbnails.prune(&hashes) {
+        Ok(0) => {},
+        Ok(removed) => println!("Removed {} thumbnails of files that are no longer stored", removed),
+        Err(e) => println!("Unable to prune the thumbnail cache. {}", e),
+    }
 
     let hostname = get().unwrap_or_default().to_string_lossy().to_string();
+
+    let tls_config = if config.tls_enabled {
+        match certificates::load_or_generate(&mut config, &hostname) {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => {
+                println!("Unable to load the tls certificate. {}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    if config.data_port == 0 {
+        config.data_port = 8081;
+    }
+
+    if config.identity_path.is_empty() {
+        config.identity_path = "photo-server-identity.key".to_string();
+    }
+    let identity = match Identity::load_or_generate(&config.identity_path) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Unable to load the server identity. {}", e);
+            return;
+        }
+    };
+    if config.enrollment_code.is_empty() {
+        config.enrollment_code = new_nonce()[..8].to_string();
+    }
+    if let Err(e) = config.save_to_file(&config.config_path) {
+        println!("Unable to save the config. {}", e);
+        return;
+    }
+    println!("Server key {}", identity.public_key());
+    println!("Enrollment code for new devices: {}", config.enrollment_code);
+
     let mut photo_server = PhotoServer::new(
         hostname.clone(),
         format!("{}:{}", hostname, port),
+        Stores::new(config.clone(), tree_store, object_store, catalog, thumbnails),
+        tls_config,
+        Arc::new(identity),
+        config.data_port,
     );
 
     if let Err(e) = photo_server.start() {
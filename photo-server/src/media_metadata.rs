@@ -0,0 +1,160 @@
+use std::{fs::File, io::BufReader, path::Path};
+use chrono::NaiveDate;
+use exif::{DateTime, Exif, In, Rational, Tag, Value};
+use shared::MediaMetadata;
+
+// reads what the stored copy says about itself, files without exif still get their dimensions if they are images
+pub fn read_media_metadata(stored_path: &Path, media_type: &str) -> MediaMetadata {
+    let mut metadata = match read_exif(stored_path) {
+        Some(exif) => from_exif(&exif),
+        None => MediaMetadata::default(),
+    };
+
+    if media_type == "image" {
+        // only the header is read, not the whole image
+        if let Ok((width, height)) = image::image_dimensions(stored_path) {
+            metadata.width = Some(width);
+            metadata.height = Some(height);
+        }
+    }
+    metadata
+}
+
+// the exif orientation alone, 1 is upright
+pub fn read_orientation(stored_path: &Path) -> Option<u32> {
+    uint(&read_exif(stored_path)?, Tag::Orientation)
+}
+
+fn read_exif(stored_path: &Path) -> Option<Exif> {
+    let file = File::open(stored_path).ok()?;
+    exif::Reader::new().read_from_container(&mut BufReader::new(file)).ok()
+}
+
+fn from_exif(exif: &Exif) -> MediaMetadata {
+    MediaMetadata {
+        captured: captured(exif),
+        camera_make: text(exif, Tag::Make),
+        camera_model: text(exif, Tag::Model),
+        lens: text(exif, Tag::LensModel),
+        exposure_time: exif.get_field(Tag::ExposureTime, In::PRIMARY).map(|field| field.display_value().to_string()),
+        f_number: rational(exif, Tag::FNumber, 0),
+        iso: uint(exif, Tag::PhotographicSensitivity),
+        focal_length: rational(exif, Tag::FocalLength, 0),
+        orientation: uint(exif, Tag::Orientation),
+        latitude: coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+        longitude: coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+        altitude: rational(exif, Tag::GPSAltitude, 0).map(|altitude| {
+            // a reference of 1 means below sea level
+            if uint(exif, Tag::GPSAltitudeRef) == Some(1) { -altitude } else { altitude }
+        }),
+        width: uint(exif, Tag::PixelXDimension),
+        height: uint(exif, Tag::PixelYDimension),
+    }
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<&[u8]> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first().map(|value| value.as_slice()),
+        _ => None,
+    }
+}
+
+fn text(exif: &Exif, tag: Tag) -> Option<String> {
+    let text = String::from_utf8_lossy(ascii(exif, tag)?).trim().to_string();
+    if text.is_empty() { None } else { Some(text) }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rationals(exif: &Exif, tag: Tag) -> Option<&Vec<Rational>> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => Some(values),
+        _ => None,
+    }
+}
+
+fn rational(exif: &Exif, tag: Tag, index: usize) -> Option<f64> {
+    let value = rationals(exif, tag)?.get(index)?;
+    if value.denom == 0 { None } else { Some(value.to_f64()) }
+}
+
+// gps coordinates are stored as unsigned degrees, minutes and seconds with the hemisphere in a separate tag
+fn coordinate(exif: &Exif, tag: Tag, reference_tag: Tag, negative_reference: u8) -> Option<f64> {
+    let degrees = rational(exif, tag, 0)?;
+    let minutes = rational(exif, tag, 1).unwrap_or(0.0);
+    let seconds = rational(exif, tag, 2).unwrap_or(0.0);
+    let coordinate = degrees + minutes / 60.0 + seconds / 3600.0;
+
+    let negative = ascii(exif, reference_tag).and_then(|reference| reference.first()) == Some(&negative_reference);
+    Some(if negative { -coordinate } else { coordinate })
+}
+
+// when the photo was taken, falling back to when the file was last changed by the camera
+fn captured(exif: &Exif) -> Option<i64> {
+    let (datetime_tag, offset_tag) = if exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_some() {
+        (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal)
+    } else {
+        (Tag::DateTime, Tag::OffsetTime)
+    };
+
+    let mut datetime = DateTime::from_ascii(ascii(exif, datetime_tag)?).ok()?;
+    if let Some(offset) = ascii(exif, offset_tag) {
+        datetime.parse_offset(offset).ok();
+    }
+    unix_seconds(&datetime)
+}
+
+// exif times without an offset are taken as utc, a leap second counts as the second before it
+fn unix_seconds(datetime: &DateTime) -> Option<i64> {
+    let utc = NaiveDate::from_ymd_opt(datetime.year as i32, datetime.month as u32, datetime.day as u32)?
+        .and_hms_opt(datetime.hour as u32, datetime.minute as u32, datetime.second.min(59) as u32)?
+        .and_utc();
+    Some(utc.timestamp() - datetime.offset.unwrap_or(0) as i64 * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8, offset: Option<i16>) -> DateTime {
+        DateTime { year, month, day, hour, minute, second, nanosecond: None, offset }
+    }
+
+    #[test]
+    fn known_timestamps() {
+        assert_eq!(unix_seconds(&datetime(1970, 1, 1, 0, 0, 0, None)), Some(0));
+        assert_eq!(unix_seconds(&datetime(2023, 7, 14, 18, 30, 5, None)), Some(1689359405));
+        assert_eq!(unix_seconds(&datetime(2016, 12, 31, 23, 59, 60, None)), Some(1483228799));
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(unix_seconds(&datetime(2000, 2, 29, 12, 0, 0, None)), Some(951825600));
+        assert_eq!(unix_seconds(&datetime(2024, 2, 29, 0, 0, 0, None)), Some(1709164800));
+        assert_eq!(unix_seconds(&datetime(2024, 3, 1, 0, 0, 0, None)), Some(1709251200));
+        assert_eq!(unix_seconds(&datetime(2023, 2, 29, 0, 0, 0, None)), None);
+        assert_eq!(unix_seconds(&datetime(1900, 2, 29, 0, 0, 0, None)), None);
+    }
+
+    #[test]
+    fn dates_before_1970() {
+        assert_eq!(unix_seconds(&datetime(1969, 12, 31, 23, 59, 59, None)), Some(-1));
+        assert_eq!(unix_seconds(&datetime(1900, 3, 1, 0, 0, 0, None)), Some(-2203891200));
+    }
+
+    #[test]
+    fn offsets_are_subtracted() {
+        assert_eq!(unix_seconds(&datetime(2000, 2, 29, 21, 0, 0, Some(9 * 60))), Some(951825600));
+        assert_eq!(unix_seconds(&datetime(1970, 1, 1, 0, 0, 0, Some(-60))), Some(3600));
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        assert_eq!(unix_seconds(&datetime(2023, 13, 1, 0, 0, 0, None)), None);
+        assert_eq!(unix_seconds(&datetime(2023, 4, 31, 0, 0, 0, None)), None);
+        assert_eq!(unix_seconds(&datetime(2023, 4, 1, 24, 0, 0, None)), None);
+        assert_eq!(unix_seconds(&datetime(0, 0, 0, 0, 0, 0, None)), None);
+    }
+}
@@ -0,0 +1,419 @@
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+use serde::{Deserialize, Serialize};
+use shared::{tree::sync_directory, StoreStats};
+
+// the journal is folded into a new snapshot once this many changes have been appended to it
+const SNAPSHOT_INTERVAL: usize = 1024;
+
+// a single copy of some content, shared by every repository path that holds the same bytes
+// the paths are hard links to the blob, so blobs are read-only: editing a repository file in place
+// would change every other path holding the same content
+#[derive(Serialize,Deserialize, Default, Debug, Clone)]
+pub struct BlobEntry {
+    pub location: String,
+    pub size: u64,
+    pub references: Vec<String>, // stored file paths that are hard links to this blob
+}
+
+// one change to the index, appended to the journal so a change doesn't rewrite every blob
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum ObjectChange {
+    Store { hash: String, location: String, size: u64, reference: String },
+    Release { reference: String },
+    Rename { from: String, to: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SequencedChange {
+    sequence: u64,
+    change: ObjectChange,
+}
+
+#[derive(Serialize,Deserialize, Default, Debug, Clone)]
+pub struct ObjectStore {
+    pub blobs: HashMap<String, BlobEntry>, // keyed by file hash
+    pub path: String,
+    #[serde(default)]
+    pub sequence: u64, // the sequence the next journaled change gets, earlier ones are already in the snapshot
+    #[serde(skip)]
+    references: HashMap<String, String>, // stored file path to the hash of its blob, rebuilt on load
+    #[serde(skip)]
+    journal_length: usize,
+}
+
+fn journal_path(path: &str) -> String {
+    format!("{}.journal", path)
+}
+
+fn make_read_only(path: &Path) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions)
+}
+
+impl ObjectStore {
+    // a missing index starts an empty store, one that can't be parsed is an error since starting over
+    // would lose every reference count and the blobs they keep alive would never be freed
+    pub fn load_from_file(path: &str) -> std::io::Result<Self> {
+        let mut object_store = match std::fs::read_to_string(path) {
+            Ok(store_content) => serde_json::from_str::<ObjectStore>(&store_content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} can't be parsed. {}", path, e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("Object store index not found, using an empty store.");
+                ObjectStore::default()
+            }
+            Err(e) => return Err(e),
+        };
+        object_store.path = path.to_string();
+        object_store.references = object_store.blobs.iter()
+            .flat_map(|(hash, entry)| entry.references.iter().map(move |reference| (reference.clone(), hash.clone())))
+            .collect();
+        object_store.replay_journal();
+        Ok(object_store)
+    }
+
+    // applies the changes journaled since the snapshot, a torn last record is dropped along with anything after it
+    fn replay_journal(&mut self) {
+        let journal = match File::open(journal_path(&self.path)) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+
+        for line in BufReader::new(journal).split(b'\n') {
+            let record = line.ok().and_then(|line| serde_json::from_slice::<SequencedChange>(&line).ok());
+            match record {
+                Some(record) if record.sequence < self.sequence => {}
+                Some(record) if record.sequence == self.sequence => {
+                    self.apply(&record.change);
+                    self.sequence += 1;
+                }
+                _ => {
+                    eprintln!("{} ends in a record that can't be replayed, it was dropped", journal_path(&self.path));
+                    break;
+                }
+            }
+            self.journal_length += 1;
+        }
+    }
+
+    // written next to path and renamed over the old index, so a crash leaves one or the other intact
+    // the journal is only cleared once the snapshot holding its changes is in place
+    pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
+        let store_content = serde_json::to_vec(self)?;
+        let temp_path = format!("{}.tmp", path);
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&store_content)?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        sync_directory(path);
+
+        let journal_path = journal_path(path);
+        if Path::new(&journal_path).exists() {
+            File::create(&journal_path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // updates the index, returning the blob a release left without references
+    fn apply(&mut self, change: &ObjectChange) -> Option<BlobEntry> {
+        match change {
+            ObjectChange::Store { hash, location, size, reference } => {
+                let entry = self.blobs.entry(hash.clone()).or_default();
+                entry.location = location.clone();
+                entry.size = *size;
+                entry.references.push(reference.clone());
+                self.references.insert(reference.clone(), hash.clone());
+                None
+            }
+            ObjectChange::Release { reference } => {
+                let hash = self.references.remove(reference)?;
+                let entry = self.blobs.get_mut(&hash)?;
+                entry.references.retain(|r| r != reference);
+                if entry.references.is_empty() { self.blobs.remove(&hash) } else { None }
+            }
+            ObjectChange::Rename { from, to } => {
+                let hash = self.references.remove(from)?;
+                if let Some(entry) = self.blobs.get_mut(&hash) {
+                    for reference in entry.references.iter_mut().filter(|r| *r == from) {
+                        *reference = to.clone();
+                    }
+                }
+                self.references.insert(to.clone(), hash);
+                None
+            }
+        }
+    }
+
+    // journals the change and then applies it, an error means the index wasn't changed
+    fn record(&mut self, change: ObjectChange) -> std::io::Result<Option<BlobEntry>> {
+        let mut record = serde_json::to_vec(&SequencedChange { sequence: self.sequence, change: change.clone() })?;
+        record.push(b'\n');
+
+        let mut journal = OpenOptions::new().create(true).append(true).open(journal_path(&self.path))?;
+        let journal_end = journal.metadata()?.len();
+        if let Err(e) = journal.write_all(&record).and_then(|_| journal.sync_data()) {
+            // a torn record would make every record appended after it unreadable
+            journal.set_len(journal_end).ok();
+            return Err(e);
+        }
+        self.sequence += 1;
+        self.journal_length += 1;
+        let orphaned = self.apply(&change);
+
+        if self.journal_length >= SNAPSHOT_INTERVAL {
+            match self.save_to_file(&self.path) {
+                Ok(()) => self.journal_length = 0,
+                Err(e) => eprintln!("Failed to write a snapshot of the object store, its journal still holds every change. {}", e),
+            }
+        }
+        Ok(orphaned)
+    }
+
+    // writes data to a private file under .objects so the caller can do it before taking any lock,
+    // store then either moves it into place or drops it if the content turns out to be stored already
+    pub fn stage_blob(storage_directory: &Path, file_hash: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+        static STAGED: AtomicU64 = AtomicU64::new(0);
+        let staging_directory = storage_directory.join(".objects").join("staging");
+        std::fs::create_dir_all(&staging_directory)?;
+        let staged_path = staging_directory.join(format!("{}.{}.{}", file_hash, std::process::id(), STAGED.fetch_add(1, Ordering::Relaxed)));
+        let mut staged_file = File::create(&staged_path)?;
+        staged_file.write_all(data)?;
+        staged_file.sync_all()?;
+        Ok(staged_path)
+    }
+
+    // stores a staged blob under its hash and makes file_path point at it, returns true if the content was already stored
+    pub fn store(&mut self, storage_directory: &Path, file_path: &Path, file_hash: &str, staged_path: &Path) -> std::io::Result<bool> {
+        let reference = file_path.to_string_lossy().to_string();
+
+        // overwriting a path drops whatever it used to point at
+        self.release_reference(&reference)?;
+
+        let existing = self.blobs.get(file_hash)
+            .filter(|entry| Path::new(&entry.location).exists())
+            .map(|entry| (PathBuf::from(&entry.location), entry.size));
+        let deduplicated = existing.is_some();
+
+        let (blob_path, size) = match existing {
+            Some(existing) => {
+                std::fs::remove_file(staged_path)?;
+                existing
+            }
+            None => {
+                let blob_path = storage_directory.join(".objects").join(&file_hash[..2]).join(file_hash);
+                if let Some(parent) = blob_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let size = std::fs::metadata(staged_path)?.len();
+                make_read_only(staged_path)?;
+                std::fs::rename(staged_path, &blob_path)?;
+                sync_directory(&blob_path.to_string_lossy());
+                (blob_path, size)
+            }
+        };
+
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if std::fs::hard_link(&blob_path, file_path).is_err() {
+            // some filesystems can't hard link, a plain copy still keeps the layout browsable
+            std::fs::copy(&blob_path, file_path)?;
+            make_read_only(file_path)?;
+        }
+
+        self.record(ObjectChange::Store {
+            hash: file_hash.to_string(),
+            location: blob_path.to_string_lossy().to_string(),
+            size,
+            reference,
+        })?;
+        Ok(deduplicated)
+    }
+
+    fn release_reference(&mut self, reference: &str) -> std::io::Result<u64> {
+        if !self.references.contains_key(reference) {
+            return Ok(0);
+        }
+        if Path::new(reference).exists() {
+            std::fs::remove_file(reference)?;
+        }
+        match self.record(ObjectChange::Release { reference: reference.to_string() })? {
+            Some(orphaned) => {
+                std::fs::remove_file(&orphaned.location).ok();
+                Ok(orphaned.size)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // drops a single stored file, returns the bytes freed if it was the last reference to its blob
+    pub fn remove(&mut self, file_path: &Path) -> std::io::Result<u64> {
+        self.release_reference(&file_path.to_string_lossy())
+    }
+
+    // moves a stored file, whatever used to be at the new path is dropped first
+    pub fn rename(&mut self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let from_reference = from.to_string_lossy().to_string();
+        let to_reference = to.to_string_lossy().to_string();
+
+        self.release_reference(&to_reference)?;
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(from, to)?;
+
+        if self.references.contains_key(&from_reference) {
+            self.record(ObjectChange::Rename { from: from_reference, to: to_reference })?;
+        }
+        Ok(())
+    }
+
+    // drops every reference below repo_path and frees the blobs no other repository uses
+    pub fn release_repository(&mut self, repo_path: &Path) -> std::io::Result<u64> {
+        let references: Vec<String> = self.references.keys()
+            .filter(|reference| Path::new(reference).starts_with(repo_path))
+            .cloned()
+            .collect();
+
+        let mut freed_bytes = 0;
+        for reference in references {
+            freed_bytes += self.release_reference(&reference)?;
+        }
+        Ok(freed_bytes)
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats::default();
+        for entry in self.blobs.values() {
+            stats.blob_count += 1;
+            stats.reference_count += entry.references.len() as u64;
+            stats.stored_bytes += entry.size;
+            stats.logical_bytes += entry.size * entry.references.len() as u64;
+        }
+        stats.saved_bytes = stats.logical_bytes.saturating_sub(stats.stored_bytes);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a store with its index and blobs in their own scratch directory, removed again when the test is done
+    struct ScratchStore {
+        directory: PathBuf,
+    }
+
+    impl ScratchStore {
+        fn new(test_name: &str) -> Self {
+            let directory = std::env::temp_dir().join(format!("photo-backup-objects-{}-{}", test_name, std::process::id()));
+            std::fs::remove_dir_all(&directory).ok();
+            std::fs::create_dir_all(&directory).unwrap();
+            ScratchStore { directory }
+        }
+
+        fn load(&self) -> ObjectStore {
+            ObjectStore::load_from_file(&self.directory.join("objects.json").to_string_lossy()).unwrap()
+        }
+
+        fn path(&self, relative_path: &str) -> PathBuf {
+            self.directory.join("repo").join(relative_path)
+        }
+
+        fn store(&self, object_store: &mut ObjectStore, relative_path: &str, hash: &str, data: &[u8]) -> bool {
+            let staged_path = ObjectStore::stage_blob(&self.directory, hash, data).unwrap();
+            object_store.store(&self.directory, &self.path(relative_path), hash, &staged_path).unwrap()
+        }
+    }
+
+    impl Drop for ScratchStore {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.directory).ok();
+        }
+    }
+
+    fn references(object_store: &ObjectStore, hash: &str) -> usize {
+        object_store.blobs.get(hash).map(|entry| entry.references.len()).unwrap_or(0)
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let scratch = ScratchStore::new("dedup");
+        let mut object_store = scratch.load();
+        assert!(!scratch.store(&mut object_store, "a.jpg", "aa11", b"same"));
+        assert!(scratch.store(&mut object_store, "b.jpg", "aa11", b"same"));
+        assert_eq!(references(&object_store, "aa11"), 2);
+
+        let stats = object_store.stats();
+        assert_eq!((stats.blob_count, stats.reference_count, stats.stored_bytes, stats.saved_bytes), (1, 2, 4, 4));
+        assert_eq!(std::fs::read(scratch.path("b.jpg")).unwrap(), b"same");
+        assert!(std::fs::metadata(scratch.path("a.jpg")).unwrap().permissions().readonly());
+    }
+
+    #[test]
+    fn the_last_reference_frees_the_blob() {
+        let scratch = ScratchStore::new("remove");
+        let mut object_store = scratch.load();
+        scratch.store(&mut object_store, "a.jpg", "aa11", b"same");
+        scratch.store(&mut object_store, "b.jpg", "aa11", b"same");
+        let blob_path = PathBuf::from(&object_store.blobs["aa11"].location);
+
+        assert_eq!(object_store.remove(&scratch.path("a.jpg")).unwrap(), 0);
+        assert_eq!(references(&object_store, "aa11"), 1);
+        assert!(!scratch.path("a.jpg").exists());
+        assert!(blob_path.exists());
+
+        assert_eq!(object_store.remove(&scratch.path("b.jpg")).unwrap(), 4);
+        assert!(object_store.blobs.is_empty());
+        assert!(!blob_path.exists());
+    }
+
+    #[test]
+    fn renames_move_the_reference() {
+        let scratch = ScratchStore::new("rename");
+        let mut object_store = scratch.load();
+        scratch.store(&mut object_store, "a.jpg", "aa11", b"first");
+        scratch.store(&mut object_store, "b.jpg", "bb22", b"second");
+
+        // renaming over b.jpg drops the only reference to its blob
+        object_store.rename(&scratch.path("a.jpg"), &scratch.path("b.jpg")).unwrap();
+        assert_eq!(object_store.blobs["aa11"].references, [scratch.path("b.jpg").to_string_lossy()]);
+        assert!(!object_store.blobs.contains_key("bb22"));
+        assert_eq!(std::fs::read(scratch.path("b.jpg")).unwrap(), b"first");
+
+        assert_eq!(object_store.remove(&scratch.path("a.jpg")).unwrap(), 0);
+        assert_eq!(object_store.remove(&scratch.path("b.jpg")).unwrap(), 5);
+    }
+
+    #[test]
+    fn overwriting_a_path_releases_its_old_content() {
+        let scratch = ScratchStore::new("overwrite");
+        let mut object_store = scratch.load();
+        scratch.store(&mut object_store, "a.jpg", "aa11", b"first");
+        scratch.store(&mut object_store, "a.jpg", "bb22", b"second");
+        assert!(!object_store.blobs.contains_key("aa11"));
+        assert_eq!(references(&object_store, "bb22"), 1);
+        assert_eq!(std::fs::read(scratch.path("a.jpg")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn the_journal_is_replayed_on_load() {
+        let scratch = ScratchStore::new("journal");
+        let mut object_store = scratch.load();
+        scratch.store(&mut object_store, "a.jpg", "aa11", b"same");
+        scratch.store(&mut object_store, "b.jpg", "aa11", b"same");
+        scratch.store(&mut object_store, "c.jpg", "cc33", b"other");
+        object_store.rename(&scratch.path("b.jpg"), &scratch.path("d.jpg")).unwrap();
+        object_store.remove(&scratch.path("c.jpg")).unwrap();
+
+        let mut reloaded = scratch.load();
+        assert_eq!(reloaded.sequence, object_store.sequence);
+        assert_eq!(reloaded.blobs["aa11"].references, object_store.blobs["aa11"].references);
+        assert!(!reloaded.blobs.contains_key("cc33"));
+
+        // the reloaded store knows which blob each path links to
+        assert_eq!(reloaded.release_repository(&scratch.directory.join("repo")).unwrap(), 4);
+        assert!(reloaded.blobs.is_empty());
+        assert!(scratch.load().blobs.is_empty());
+    }
+}
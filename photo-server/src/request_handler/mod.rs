@@ -1,47 +1,95 @@
-use std::{collections::HashMap, net::{TcpStream}, sync::{Arc,atomic}};
+use std::sync::{Arc, atomic};
 use serde_json;
-use shared::{read_request, send_response, Request, RequestTypes, Response, ResponseCodes, Tree};
+use shared::{read_request, send_response, Request, RequestTypes, Response, ResponseCodes, Stream};
 
-use request_handler_utils::ServerConfig;
+use request_handler_utils::{update_config, ServerConfig};
+use crate::server::Stores;
+use crate::filestreamserver::SessionRegistry;
 
 pub mod request_handler_utils;
 mod server_repository_management;
+mod server_file_transfer;
+mod server_access_control;
 pub struct PhotoServerRequestHandler {
-    pub stream:TcpStream,
-    pub config:ServerConfig,
+    pub stream:Stream,
+    pub config:ServerConfig, // this request's copy of the shared config, changes go through update_config
+    pub user:String, // the account the authenticated device acts as
+    pub device:String,
     pub batch_processor_context: Option<(std::thread::JoinHandle<()>, Arc<atomic::AtomicBool>)>,
-    pub trees:HashMap<String, Tree>
+    pub stores: Stores,
+    pub sessions: SessionRegistry,
+    pub batch_session: Option<String>, // token of the batch processor this connection started
 }
 
 impl PhotoServerRequestHandler {
-    pub fn new(config_path:String, stream:TcpStream) -> Self {
-        PhotoServerRequestHandler {
+    pub fn new(stream:Stream, user:String, device:String, stores:Stores, sessions:SessionRegistry) -> anyhow::Result<Self> {
+        let mut request_handler = PhotoServerRequestHandler {
             stream,
-            config: ServerConfig::load_from_file(&config_path),
+            config: ServerConfig::default(),
+            user,
+            device,
             batch_processor_context: None,
-            trees: HashMap::new(),
+            stores,
+            sessions,
+            batch_session: None,
+        };
+        request_handler.refresh_config()?;
+        Ok(request_handler)
+    }
+
+    // picks up roles granted and repositories created by other connections
+    fn refresh_config(&mut self) -> anyhow::Result<()> {
+        self.config = self.stores.config.lock()
+            .map_err(|e| anyhow::anyhow!("config lock poisoned: {}", e))?
+            .clone();
+        Ok(())
+    }
+
+    // changes the shared config and saves it, this request's copy is refreshed along with it
+    fn update_config<T>(&mut self, update: impl FnOnce(&mut ServerConfig) -> T) -> anyhow::Result<T> {
+        let (result, config) = update_config(&self.stores.config, update)?;
+        self.config = config;
+        Ok(result)
+    }
+
+    // forgets the session token so a file stream can no longer claim the batch processor
+    fn close_batch_session(&mut self) {
+        if let Some(session_token) = self.batch_session.take()
+            && let Ok(mut sessions) = self.sessions.lock() {
+            sessions.remove(&session_token);
         }
     }
 
     pub fn run(&mut self) -> anyhow::Result<()> {
         println!("Launching a request handler");
-        for entry in std::fs::read_dir("trees")? {
-            let entry = entry?;
-            let path = entry.path();
-            let tree = Tree::load_from_file(path.to_str().unwrap());
-            let repo_name = path.file_stem().unwrap().to_string_lossy().to_string();
-            self.trees.insert(repo_name, tree);
-        }
         loop {
             let request = read_request(&mut self.stream)?;
+
+            self.refresh_config()?;
+            if let Err(message) = self.authorize(&request) {
+                self.deny(message)?;
+                continue;
+            }
+
             match request.request_type {
                 RequestTypes::GetRepos => self.get_repos()?,
                 RequestTypes::CreateRepo => self.create_repo(request)?,
-                RequestTypes::StartBatchProcessor => self.start_batch_processor()?,
+                RequestTypes::StartBatchProcessor => self.start_batch_processor(request)?,
                 RequestTypes::EndBatchProcessor => self.end_batch_processor()?,
                 RequestTypes::RemoveRepository => self.remove_repository(request)?,
                 RequestTypes::GetRepoTree => self.get_repo_tree(request)?,
                 RequestTypes::SetStoragePath => self.set_storage_path(request)?,
+                RequestTypes::DownloadFile => self.download_file(request)?,
+                RequestTypes::RestoreRepository => self.restore_repository(request)?,
+                RequestTypes::GetStoreStats => self.get_store_stats()?,
+                RequestTypes::SetRepoAccess => self.set_repo_access(request)?,
+                RequestTypes::GetFileMetadata => self.get_file_metadata(request)?,
+                RequestTypes::GetThumbnail => self.get_thumbnail(request)?,
+                RequestTypes::Search => self.search(request)?,
+                RequestTypes::GetRepoStats => self.get_repo_stats(request)?,
+                RequestTypes::SetRepoQuota => self.set_repo_quota(request)?,
+                RequestTypes::GetPendingDevices => self.get_pending_devices()?,
+                RequestTypes::ApproveDevice => self.approve_device(request)?,
             }
         }
     }
@@ -64,8 +112,7 @@ impl PhotoServerRequestHandler {
                 };
 
             } else {
-                self.config.storage_directory = storage_directory;
-                self.config.save_to_file(&self.config.config_path);
+                self.update_config(|config| config.storage_directory = storage_directory)?;
                 
                 response = Response {
                     status_code:ResponseCodes::OK,
@@ -77,10 +124,31 @@ impl PhotoServerRequestHandler {
             Ok(())
     }
 
+    fn get_store_stats(&mut self) -> anyhow::Result<()> {
+        let stats = match self.stores.object_store.lock() {
+            Ok(object_store) => object_store.stats(),
+            Err(e) => return Err(anyhow::anyhow!("object store lock poisoned: {}", e)),
+        };
+
+        let response = Response {
+            status_code: ResponseCodes::OK,
+            status_message: format!("{} bytes saved by deduplication", stats.saved_bytes),
+            body: serde_json::to_vec(&stats)?,
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
     fn get_repos(&mut self) -> anyhow::Result<()> {
         let response:Response;
 
-        if self.config.repo_list.is_empty() {
+        // users only see the repositories they have been given a role in
+        let available_repositories: Vec<String> = self.config.repo_list.iter()
+            .filter(|repo| self.config.role(repo, &self.user).is_some())
+            .cloned()
+            .collect();
+
+        if available_repositories.is_empty() {
             response = Response {
                 status_code: ResponseCodes::Empty,
                 status_message: "Empty config".to_string(),
@@ -88,8 +156,6 @@ impl PhotoServerRequestHandler {
             };
 
         } else {
-            let available_repositories = self.config.repo_list.clone();
-
             response  = Response {
                 status_code: ResponseCodes::OK,
                 status_message: "OK".to_string(),
@@ -100,4 +166,14 @@ impl PhotoServerRequestHandler {
         send_response(response, &mut self.stream)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for PhotoServerRequestHandler {
+    // a client that drops the command connection without ending its batch processor must not leave it claimable
+    fn drop(&mut self) {
+        if let Some((_, stop_flag)) = &self.batch_processor_context {
+            stop_flag.store(true, atomic::Ordering::Relaxed);
+        }
+        self.close_batch_session();
+    }
+}
@@ -1,47 +1,188 @@
+use std::{collections::HashMap, fs::File, io::Write, sync::{Arc, Mutex}};
 use serde::{Deserialize, Serialize};
-use serde_json;
+use shared::{tree::sync_directory, PendingDevice, RepoQuota, Role};
+
+// the one copy of the config every connection reads, changes are saved under the lock so none are lost
+pub type SharedConfig = Arc<Mutex<ServerConfig>>;
+
+// applies update to a copy of the shared config and only swaps it in once the copy is saved,
+// returns the update's result along with the config as it now is
+pub fn update_config<T>(config:&SharedConfig, update: impl FnOnce(&mut ServerConfig) -> T) -> anyhow::Result<(T, ServerConfig)> {
+    let mut config = config.lock()
+        .map_err(|e| anyhow::anyhow!("config lock poisoned: {}", e))?;
+    let mut updated = config.clone();
+    let result = update(&mut updated);
+    updated.save_to_file(&updated.config_path)?;
+    *config = updated.clone();
+    Ok((result, updated))
+}
 
 #[derive(Serialize,Deserialize, Default, Debug, Clone)]
 pub struct ServerConfig {
     pub storage_directory:String,
     pub repo_list: Vec<String>,
     pub config_path: String,
+    #[serde(default)]
+    pub data_port: u16, // every batch processor receives its file stream on this port
+    #[serde(default)]
+    pub tls_enabled: bool,
+    #[serde(default)]
+    pub certificate_path: String,
+    #[serde(default)]
+    pub private_key_path: String,
+    #[serde(default)]
+    pub identity_path: String,
+    #[serde(default)]
+    pub enrollment_code: String, // a new device must present this the first time it connects
+    #[serde(default)]
+    pub enrolled_devices: Vec<EnrolledDevice>,
+    #[serde(default)]
+    pub pending_devices: Vec<PendingDevice>, // waiting to join a user that already has devices
+    #[serde(default)]
+    pub admins: Vec<String>, // users that own every repository and may change server wide settings
+    #[serde(default)]
+    pub repo_access: HashMap<String, HashMap<String, Role>>, // repo name -> user name -> role
+    #[serde(default)]
+    pub repo_quotas: HashMap<String, RepoQuota>, // repositories without one are unlimited
+}
+
+#[derive(Serialize,Deserialize, Default, Debug, Clone)]
+pub struct EnrolledDevice {
+    pub name: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub user: String, // the account this device acts as, devices enrolled before accounts existed act as their own name
+}
+
+// a repository is one directory in the storage directory and one tree in trees/, so its name has to be a
+// single plain path component that can't point at the storage directory itself or climb out of it
+pub fn is_valid_repo_name(repo_name:&str) -> bool {
+    let mut components = std::path::Path::new(repo_name).components();
+    matches!((components.next(), components.next()), (Some(std::path::Component::Normal(_)), None))
+        && !repo_name.contains(['/', '\\', ':'])
+}
+
+impl EnrolledDevice {
+    pub fn user_name(&self) -> &str {
+        if self.user.is_empty() { &self.name } else { &self.user }
+    }
 }
 
 impl ServerConfig {
-    pub fn load_from_file(path: &str) -> Self {
-        let config_content = std::fs::read_to_string(path)
-        .unwrap_or_else(|_| {
+    // a missing or empty file starts the default configuration, one that can't be parsed is an error
+    // since saving the default over it would drop every enrolled device, admin, role and quota
+    pub fn load_from_file(path: &str) -> std::io::Result<Self> {
+        let config_content = match std::fs::read_to_string(path) {
+            Ok(config_content) => config_content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        if config_content.trim().is_empty() {
             println!("Config file not found, using default configuration.");
-            String::new()
-        });
-        serde_json::from_str(&config_content).unwrap_or_else(|_| {
-            println!("Failed to parse config file, using default configuration.");
-            ServerConfig::default()
-        })
+            return Ok(ServerConfig::default());
+        }
+        serde_json::from_str(&config_content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} can't be parsed. {}", path, e)))
     }
 
-    pub fn save_to_file(&self, path: &str) {
-        if let Ok(config_content) = serde_json::to_string_pretty(self) {
-            if let Err(e) = std::fs::write(path, config_content) {
-                eprintln!("Failed to write config file: {}", e);
-
-            }
-        }   
+    // written next to path and renamed over the old config, so a crash leaves one or the other intact
+    pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
+        let config_content = serde_json::to_string_pretty(self)?;
+        let temp_path = format!("{}.tmp", path);
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(config_content.as_bytes())?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        sync_directory(path);
+        Ok(())
     }
+
     pub fn remove_repo(&mut self, repo:String) {
         if self.repo_list.contains(&repo) {
             self.repo_list.retain(|r| r != &repo);
-            self.save_to_file(&self.config_path);
+            self.repo_access.remove(&repo);
+            self.repo_quotas.remove(&repo);
         } else {
             eprintln!("Repo does not exist in config.");
         }
     }
     
+    pub fn enrolled_device(&self, public_key:&str) -> Option<&EnrolledDevice> {
+        self.enrolled_devices.iter().find(|d| d.public_key == public_key)
+    }
+
+    pub fn is_enrolled(&self, public_key:&str) -> bool {
+        self.enrolled_device(public_key).is_some()
+    }
+
+    // the first user to enroll a device administers the server
+    pub fn enroll_device(&mut self, name:String, public_key:String, user:String) {
+        if !self.is_enrolled(&public_key) {
+            if self.admins.is_empty() {
+                self.admins.push(user.clone());
+            }
+            self.enrolled_devices.push(EnrolledDevice { name, public_key, user });
+        }
+    }
+
+    // a user exists once a device is enrolled under it or it has been given a role
+    pub fn user_exists(&self, user:&str) -> bool {
+        self.is_admin(user)
+            || self.enrolled_devices.iter().any(|d| d.user_name() == user)
+            || self.repo_access.values().any(|users| users.contains_key(user))
+    }
+
+    pub fn add_pending_device(&mut self, device:PendingDevice) {
+        if !self.pending_devices.iter().any(|d| d.public_key == device.public_key) {
+            self.pending_devices.push(device);
+        }
+    }
+
+    // removes the pending device, enrolling it under the user it asked for when approved
+    pub fn resolve_pending_device(&mut self, public_key:&str, approve:bool) -> Option<PendingDevice> {
+        let index = self.pending_devices.iter().position(|d| d.public_key == public_key)?;
+        let device = self.pending_devices.remove(index);
+        if approve {
+            self.enroll_device(device.device_name.clone(), device.public_key.clone(), device.user_name.clone());
+        }
+        Some(device)
+    }
+
+    pub fn is_admin(&self, user:&str) -> bool {
+        self.admins.iter().any(|a| a == user)
+    }
+
+    // admins own every repository, including ones created before access control existed
+    pub fn role(&self, repo:&str, user:&str) -> Option<Role> {
+        if self.is_admin(user) {
+            return Some(Role::Owner);
+        }
+        self.repo_access.get(repo).and_then(|users| users.get(user)).copied()
+    }
+
+    pub fn set_role(&mut self, repo:&str, user:&str, role:Option<Role>) {
+        let users = self.repo_access.entry(repo.to_string()).or_default();
+        match role {
+            Some(role) => { users.insert(user.to_string(), role); },
+            None => { users.remove(user); },
+        }
+    }
+
+    pub fn quota(&self, repo:&str) -> RepoQuota {
+        self.repo_quotas.get(repo).copied().unwrap_or_default()
+    }
+
+    pub fn set_quota(&mut self, repo:&str, quota:RepoQuota) {
+        if quota == RepoQuota::default() {
+            self.repo_quotas.remove(repo);
+        } else {
+            self.repo_quotas.insert(repo.to_string(), quota);
+        }
+    }
+
     pub fn add_repo(&mut self, repo:String) {
         if !self.repo_list.contains(&repo) {
             self.repo_list.push(repo);
-            self.save_to_file(&self.config_path);
         } else {
             eprintln!("Repo already exists in config.");
         }
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use shared::{send_response, DeviceApproval, PendingDevice, RepoAccess, Request, RequestTypes, Response, ResponseCodes, Role};
+
+use super::PhotoServerRequestHandler;
+use super::request_handler_utils::ServerConfig;
+
+// the repository a request targets, bodies are either the bare repo name or json with a repo_name field
+fn request_repo_name(request:&Request) -> String {
+    if let Ok(body) = serde_json::from_slice::<HashMap<String, serde_json::Value>>(&request.body) {
+        return body.get("repo_name")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+    }
+    String::from_utf8_lossy(&request.body)
+        .trim()
+        .replace(|c: char| c.is_control(), "_")
+        .to_string()
+}
+
+// every request type has to be listed here so a new one can't slip through without a decision
+fn authorize(config:&ServerConfig, user:&str, request:&Request) -> Result<(), String> {
+    let required_role = match request.request_type {
+        // any user may create repositories and see their own, uploads are checked per file by the batch processor
+        // and searches only look in the repositories the user can read
+        RequestTypes::GetRepos
+        | RequestTypes::CreateRepo
+        | RequestTypes::StartBatchProcessor
+        | RequestTypes::EndBatchProcessor
+        | RequestTypes::GetStoreStats
+        | RequestTypes::Search
+        // users only see and approve devices waiting to join their own account, admins see every one
+        | RequestTypes::GetPendingDevices
+        | RequestTypes::ApproveDevice => return Ok(()),
+        RequestTypes::SetStoragePath => {
+            if config.is_admin(user) {
+                return Ok(());
+            }
+            return Err(format!("{} is not a server admin and can't change the storage path", user));
+        }
+        // quotas protect the server's disk, so an owner can't lift the one on their own repository
+        RequestTypes::SetRepoQuota => {
+            if config.is_admin(user) {
+                return Ok(());
+            }
+            return Err(format!("{} is not a server admin and can't change quotas", user));
+        }
+        RequestTypes::GetRepoTree
+        | RequestTypes::DownloadFile
+        | RequestTypes::RestoreRepository
+        | RequestTypes::GetFileMetadata
+        | RequestTypes::GetThumbnail
+        | RequestTypes::GetRepoStats => Role::Reader,
+        RequestTypes::RemoveRepository
+        | RequestTypes::SetRepoAccess => Role::Owner,
+    };
+
+    let repo_name = request_repo_name(request);
+    match config.role(&repo_name, user) {
+        Some(role) if role >= required_role => Ok(()),
+        Some(role) => Err(format!("{} is a {} of {} but needs to be a {}", user, role, repo_name, required_role)),
+        None => Err(format!("{} has no access to {}", user, repo_name)),
+    }
+}
+
+impl PhotoServerRequestHandler {
+
+    pub fn authorize(&self, request:&Request) -> Result<(), String> {
+        authorize(&self.config, &self.user, request)
+    }
+
+    pub fn deny(&mut self, message:String) -> anyhow::Result<()> {
+        println!("{}", message);
+        let response = Response {
+            status_code: ResponseCodes::Unauthorized,
+            status_message: "Unauthorized".to_string(),
+            body: message.as_bytes().to_vec(),
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn set_repo_access(&mut self, request:Request) -> anyhow::Result<()> {
+        let access: RepoAccess = serde_json::from_slice(&request.body)?;
+
+        let response = if !self.config.repo_list.contains(&access.repo_name) {
+            Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "Repo not found".to_string(),
+                body: format!("{} does not exist", access.repo_name).as_bytes().to_vec(),
+            }
+        } else if access.user_name == self.user && access.role != Some(Role::Owner) {
+            // an owner demoting themselves could leave a repository nobody can manage
+            Response {
+                status_code: ResponseCodes::Unauthorized,
+                status_message: "Unauthorized".to_string(),
+                body: "You can't change your own role".as_bytes().to_vec(),
+            }
+        } else {
+            self.update_config(|config| config.set_role(&access.repo_name, &access.user_name, access.role))?;
+            let message = match access.role {
+                Some(role) => format!("{} is now a {} of {}", access.user_name, role, access.repo_name),
+                None => format!("{} no longer has access to {}", access.user_name, access.repo_name),
+            };
+            Response {
+                status_code: ResponseCodes::OK,
+                status_message: "OK".to_string(),
+                body: message.as_bytes().to_vec(),
+            }
+        };
+
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    // whether this user may let the pending device in, admins and the account it wants to join can
+    fn may_approve(&self, device:&PendingDevice) -> bool {
+        self.config.is_admin(&self.user) || device.user_name == self.user
+    }
+
+    pub fn get_pending_devices(&mut self) -> anyhow::Result<()> {
+        let pending_devices: Vec<&PendingDevice> = self.config.pending_devices.iter()
+            .filter(|device| self.may_approve(device))
+            .collect();
+
+        let response = Response {
+            status_code: if pending_devices.is_empty() { ResponseCodes::Empty } else { ResponseCodes::OK },
+            status_message: format!("{} devices waiting for approval", pending_devices.len()),
+            body: serde_json::to_vec(&pending_devices)?,
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn approve_device(&mut self, request:Request) -> anyhow::Result<()> {
+        let approval: DeviceApproval = serde_json::from_slice(&request.body)?;
+
+        let pending_device = self.config.pending_devices.iter()
+            .find(|device| device.public_key == approval.public_key)
+            .cloned();
+        let response = match pending_device {
+            None => Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "Device not found".to_string(),
+                body: "No device with that key is waiting for approval".as_bytes().to_vec(),
+            },
+            Some(device) if !self.may_approve(&device) => {
+                return self.deny(format!("{} can't approve devices for {}", self.user, device.user_name));
+            }
+            Some(device) => {
+                self.update_config(|config| config.resolve_pending_device(&approval.public_key, approval.approve))?;
+                let message = if approval.approve {
+                    format!("{} is now enrolled for {}", device.device_name, device.user_name)
+                } else {
+                    format!("{} was turned away", device.device_name)
+                };
+                println!("{} by {}", message, self.user);
+                Response {
+                    status_code: ResponseCodes::OK,
+                    status_message: "OK".to_string(),
+                    body: message.as_bytes().to_vec(),
+                }
+            }
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // alice owns photos, bob writes to it, carol reads it, root is an admin and dave has no access
+    fn config() -> ServerConfig {
+        let mut config = ServerConfig { admins: vec!["root".to_string()], ..Default::default() };
+        config.set_role("photos", "alice", Some(Role::Owner));
+        config.set_role("photos", "bob", Some(Role::Writer));
+        config.set_role("photos", "carol", Some(Role::Reader));
+        config
+    }
+
+    fn request(request_type: RequestTypes, body: &str) -> Request {
+        Request { request_type, body: body.as_bytes().to_vec() }
+    }
+
+    fn allowed(user: &str, request: &Request) -> bool {
+        authorize(&config(), user, request).is_ok()
+    }
+
+    #[test]
+    fn readers_can_read() {
+        let tree = request(RequestTypes::GetRepoTree, "photos");
+        assert!(["root", "alice", "bob", "carol"].iter().all(|user| allowed(user, &tree)));
+        assert!(!allowed("dave", &tree));
+    }
+
+    #[test]
+    fn only_owners_manage_a_repository() {
+        let access = request(RequestTypes::SetRepoAccess, r#"{"repo_name":"photos","user_name":"dave","role":"Reader"}"#);
+        let remove = request(RequestTypes::RemoveRepository, "photos");
+        for request in [&access, &remove] {
+            assert!(allowed("root", request));
+            assert!(allowed("alice", request));
+            assert!(!allowed("bob", request));
+            assert!(!allowed("carol", request));
+            assert!(!allowed("dave", request));
+        }
+    }
+
+    #[test]
+    fn only_admins_change_server_settings() {
+        for request_type in [RequestTypes::SetStoragePath, RequestTypes::SetRepoQuota] {
+            let request = request(request_type, r#"{"repo_name":"photos"}"#);
+            assert!(allowed("root", &request));
+            assert!(!allowed("alice", &request));
+        }
+    }
+
+    #[test]
+    fn roles_only_apply_to_their_repository() {
+        let tree = request(RequestTypes::GetRepoTree, "videos");
+        assert!(allowed("root", &tree));
+        assert!(!allowed("alice", &tree));
+    }
+
+    #[test]
+    fn unscoped_requests_need_no_role() {
+        for request_type in [RequestTypes::GetRepos, RequestTypes::CreateRepo, RequestTypes::StartBatchProcessor, RequestTypes::Search] {
+            assert!(allowed("dave", &request(request_type, "")));
+        }
+    }
+}
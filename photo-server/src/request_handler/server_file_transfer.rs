@@ -0,0 +1,214 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use shared::{media_type, send_job, send_response, Codec, FileEntry, FileHeader, Job, Request, Response, ResponseCodes, ThumbnailRequest};
+use crate::filestreamserver::stored_file_path;
+
+use super::PhotoServerRequestHandler;
+
+impl PhotoServerRequestHandler {
+
+    // the tracked files below directory, copied out so the tree store isn't held while they are streamed
+    fn tracked_files(&self, repo_name:&str, directory:&str) -> anyhow::Result<Option<Vec<(String, FileEntry)>>> {
+        let tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+        Ok(tree_store.get(repo_name).map(|tree| tree.files_in_directory(directory)
+            .into_iter()
+            .filter_map(|relative_path| tree.files.get(&relative_path).cloned().map(|entry| (relative_path, entry)))
+            .collect()))
+    }
+
+    fn tracked_file(&self, repo_name:&str, relative_path:&str) -> anyhow::Result<Option<FileEntry>> {
+        let tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+        Ok(tree_store.get(repo_name).and_then(|tree| tree.files.get(relative_path).cloned()))
+    }
+
+    // reads a stored file back into a job so it can be streamed with the batch framing
+    fn load_job(&self, repo_name:&str, relative_path:&str, entry:&FileEntry) -> anyhow::Result<Job> {
+        let file_path = stored_file_path(Path::new(&self.config.storage_directory), repo_name, relative_path)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a valid path inside the repository", relative_path))?;
+        let data = std::fs::read(&file_path)?;
+
+        let file_name = file_path.file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string();
+
+        let file_ext = file_path.extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let file_header = FileHeader {
+            repo_name: repo_name.to_string(),
+            file_name,
+            file_size: data.len(),
+            file_location: entry.file_location.clone(),
+            relative_path: relative_path.to_string(),
+            file_ext,
+            file_datetime: entry.created,
+            file_hash: entry.hash.clone(), // what was uploaded, so the client's check catches a copy that rotted in storage
+            upload_id: String::new(),
+            codec: Codec::None,
+        };
+
+        Ok(Job { file_header, data })
+    }
+
+    pub fn download_file(&mut self, request:Request) -> anyhow::Result<()> {
+        let body = serde_json::from_slice::<HashMap<String, String>>(&request.body)?;
+        let repo_name = body.get("repo_name").cloned().unwrap_or_default();
+        let relative_path = body.get("path").cloned().unwrap_or_default();
+
+        let job = self.tracked_file(&repo_name, &relative_path)?
+            .ok_or_else(|| anyhow::anyhow!("{} is not tracked", relative_path))
+            .and_then(|entry| self.load_job(&repo_name, &relative_path, &entry));
+
+        match job {
+            Ok(job) => {
+                let response = Response {
+                    status_code: ResponseCodes::OK,
+                    status_message: "OK".to_string(),
+                    body: format!("Downloading {} ({} bytes)", job.file_header.file_name, job.file_header.file_size).as_bytes().to_vec(),
+                };
+                send_response(response, &mut self.stream)?;
+                send_job(&job, &mut self.stream)?;
+            }
+            Err(e) => {
+                let response = Response {
+                    status_code: ResponseCodes::NotFound,
+                    status_message: "File not found".to_string(),
+                    body: format!("{} is not stored in {} | {}", relative_path, repo_name, e).as_bytes().to_vec(),
+                };
+                send_response(response, &mut self.stream)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_file_metadata(&mut self, request:Request) -> anyhow::Result<()> {
+        let body = serde_json::from_slice::<HashMap<String, String>>(&request.body)?;
+        let repo_name = body.get("repo_name").cloned().unwrap_or_default();
+        let relative_path = body.get("path").cloned().unwrap_or_default();
+
+        let record = self.stores.catalog.lock()
+            .map_err(|e| anyhow::anyhow!("catalog lock poisoned: {}", e))?
+            .file(&repo_name, &relative_path)?;
+
+        let response = match record {
+            Some(record) => Response {
+                status_code: ResponseCodes::OK,
+                status_message: "OK".to_string(),
+                body: serde_json::to_vec(&record.metadata())?,
+            },
+            None => Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "File not found".to_string(),
+                body: format!("{} is not cataloged in {}", relative_path, repo_name).as_bytes().to_vec(),
+            },
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn get_thumbnail(&mut self, request:Request) -> anyhow::Result<()> {
+        let thumbnail_request: ThumbnailRequest = serde_json::from_slice(&request.body)?;
+        let relative_path = &thumbnail_request.path;
+
+        let file_path = stored_file_path(Path::new(&self.config.storage_directory), &thumbnail_request.repo_name, relative_path);
+        let response = match (self.tracked_file(&thumbnail_request.repo_name, relative_path)?, file_path) {
+            (Some(entry), Some(file_path)) if media_type(relative_path) == "image" => {
+                match self.stores.thumbnails.get(&entry.hash, &file_path, thumbnail_request.size) {
+                    Ok((size, thumbnail)) => Response {
+                        status_code: ResponseCodes::OK,
+                        status_message: format!("{}px thumbnail of {}", size, relative_path),
+                        body: thumbnail,
+                    },
+                    Err(e) => Response {
+                        status_code: ResponseCodes::InternalError,
+                        status_message: "Thumbnail failed".to_string(),
+                        body: format!("Unable to make a thumbnail of {} | {}", relative_path, e).as_bytes().to_vec(),
+                    },
+                }
+            }
+            (Some(_), _) => Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "No thumbnail".to_string(),
+                body: format!("{} is not an image", relative_path).as_bytes().to_vec(),
+            },
+            (None, _) => Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "File not found".to_string(),
+                body: format!("{} is not stored in {}", relative_path, thumbnail_request.repo_name).as_bytes().to_vec(),
+            },
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn restore_repository(&mut self, request:Request) -> anyhow::Result<()> {
+        let body = serde_json::from_slice::<HashMap<String, String>>(&request.body)?;
+        let repo_name = body.get("repo_name").cloned().unwrap_or_default();
+        let directory = body.get("directory").cloned().unwrap_or_default();
+
+        let tracked_files = match self.tracked_files(&repo_name, &directory)? {
+            Some(tracked_files) => tracked_files,
+            None => {
+                let response = Response {
+                    status_code: ResponseCodes::NotFound,
+                    status_message: "Tree not found".to_string(),
+                    body: format!("{} has no tree to restore from", repo_name).as_bytes().to_vec(),
+                };
+                send_response(response, &mut self.stream)?;
+                return Ok(());
+            }
+        };
+
+        // only promise the client files that are actually on disk
+        let storage_directory = PathBuf::from(&self.config.storage_directory);
+        let tracked_files: Vec<(String, FileEntry)> = tracked_files.into_iter()
+            .filter(|(relative_path, _)| stored_file_path(&storage_directory, &repo_name, relative_path)
+                .is_some_and(|file_path| file_path.exists()))
+            .collect();
+        let manifest: Vec<&String> = tracked_files.iter().map(|(relative_path, _)| relative_path).collect();
+
+        let response = if manifest.is_empty() {
+            Response {
+                status_code: ResponseCodes::Empty,
+                status_message: "Nothing to restore".to_string(),
+                body: Vec::new(),
+            }
+        } else {
+            Response {
+                status_code: ResponseCodes::OK,
+                status_message: format!("Restoring {} files from {}", manifest.len(), directory),
+                body: serde_json::to_vec(&manifest)?,
+            }
+        };
+        send_response(response, &mut self.stream)?;
+
+        // every file gets its own response ahead of it, so one that can't be read is skipped instead of cutting the stream short
+        for (relative_path, entry) in &tracked_files {
+            match self.load_job(&repo_name, relative_path, entry) {
+                Ok(job) => {
+                    let response = Response {
+                        status_code: ResponseCodes::OK,
+                        status_message: "OK".to_string(),
+                        body: relative_path.as_bytes().to_vec(),
+                    };
+                    send_response(response, &mut self.stream)?;
+                    send_job(&job, &mut self.stream)?;
+                }
+                Err(e) => {
+                    println!("Unable to restore {} from {}. {}", relative_path, repo_name, e);
+                    let response = Response {
+                        status_code: ResponseCodes::InternalError,
+                        status_message: "Unreadable file".to_string(),
+                        body: format!("{} could not be read | {}", relative_path, e).as_bytes().to_vec(),
+                    };
+                    send_response(response, &mut self.stream)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
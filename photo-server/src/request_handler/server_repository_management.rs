@@ -1,11 +1,11 @@
 use std::{collections::HashMap, path::Path};
 use serde_json;
-use shared::{send_response, Request, Response, ResponseCodes, Tree};
-use rand::Rng;
+use shared::{auth::new_nonce, send_response, BatchProcessorOffer, Codec, RepoQuotaUpdate, Request, Response, ResponseCodes, Role, SearchQuery, TreeSync};
 use crate::filestreamserver::{initiate_batch_processor};
-use std::{net::{TcpListener}, sync::{Arc,atomic}, path::PathBuf};
+use std::sync::{Arc, atomic, mpsc};
 
 use super::PhotoServerRequestHandler;
+use super::request_handler_utils::is_valid_repo_name;
 
 impl PhotoServerRequestHandler {
 
@@ -15,13 +15,31 @@ impl PhotoServerRequestHandler {
             .replace(|c: char| c.is_control(), "_")
             .to_string();
 
-        self.config.remove_repo(repo_name.clone());
-        if let Some(tree) = self.trees.get(&repo_name) {
-            std::fs::remove_file(&tree.path)?;
+        if !is_valid_repo_name(&repo_name) {
+            let response = Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "Invalid repo name".to_string(),
+                body: format!("{} is not a repository name", repo_name).as_bytes().to_vec(),
+            };
+            send_response(response, &mut self.stream)?;
+            return Ok(());
         }
 
+        self.update_config(|config| config.remove_repo(repo_name.clone()))?;
+        self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?
+            .remove(&repo_name)?;
+
         let repo_path = std::path::Path::new(&self.config.storage_directory).join(&repo_name);
+        // free the blobs only this repository was using before the links disappear
+        let freed_bytes = self.stores.object_store.lock()
+            .map_err(|e| anyhow::anyhow!("object store lock poisoned: {}", e))?
+            .release_repository(&repo_path)?;
+        println!("freed {} bytes from the object store", freed_bytes);
         std::fs::remove_dir_all(repo_path)?;
+        self.stores.catalog.lock()
+            .map_err(|e| anyhow::anyhow!("catalog lock poisoned: {}", e))?
+            .remove_repository(&repo_name)?;
 
         let response = Response {
             status_code: ResponseCodes::OK,
@@ -41,43 +59,40 @@ impl PhotoServerRequestHandler {
         
         let response:Response;
 
-        if repo_path.exists() {
+        if !is_valid_repo_name(&repo_name) {
+            response = Response {
+                status_code: ResponseCodes::InternalError,
+                status_message: "Invalid repo name".to_string(),
+                body: format!("{} can't be used as a repository name, it has to be a single folder name", repo_name).as_bytes().to_vec(),
+            };
+        } else if repo_path.exists() {
             response = Response {
                 status_code: ResponseCodes::Duplicate,
                 status_message: "Err".to_string(),
                 body: "A repo with the same name already exists".as_bytes().to_vec()
             };
+        } else if let Err(e) = std::fs::create_dir(repo_path) {
+            response = Response {
+                status_code: ResponseCodes::InternalError,
+                status_message: "Failed to create the repo directory".to_string(),
+                body: e.to_string().as_bytes().to_vec(),
+            };
         } else {
+            // only registered once its directory exists, whoever creates a repository owns it
+            let user = self.user.clone();
+            self.update_config(|config| {
+                config.add_repo(repo_name.clone());
+                config.set_role(&repo_name, &user, Some(Role::Owner));
+            })?;
 
-            let mut response_message = format!("Successfully created new repository | {}", repo_name);
-            let mut status_code = ResponseCodes::OK;
-            let mut status_message = "OK";
-            
-            if let Err(e) = std::fs::create_dir(repo_path) {
-                response_message = e.to_string();
-                status_code = ResponseCodes::InternalError;
-                status_message = "Failed to create the repo directory";
-            }
-
-            // save it to the config
-            self.config.add_repo(repo_name.clone());
-            self.config.save_to_file(&self.config.config_path);
-
-            // load a tree
-            let tree:Tree = Tree {
-                version: 0,
-                content: HashMap::new(),
-                history: HashMap::new(),
-                path: ("trees".to_string() + "/" + &repo_name + ".tree").to_string(),
-                name: repo_name.clone(),
-            };
-            tree.save_to_file(&tree.path);
-            self.trees.insert(repo_name, tree);
+            self.stores.tree_store.lock()
+                .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?
+                .create(&repo_name);
             
             response = Response {
-                status_code: status_code,
-                status_message: status_message.to_string(),
-                body: response_message.as_bytes().to_vec(),
+                status_code: ResponseCodes::OK,
+                status_message: "OK".to_string(),
+                body: format!("Successfully created new repository | {}", repo_name).as_bytes().to_vec(),
             }
         }
         
@@ -92,39 +107,39 @@ impl PhotoServerRequestHandler {
             .unwrap_or("")
             .to_string();
         let client_version = body.get("version")
-            .and_then(|v| v.as_u64())
+            .and_then(|v| v.as_i64())
             .unwrap_or(0) as i32;
+        let snapshot = body.get("snapshot")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
         let response: Response;
-        //update the tree from the disk because the file streaming server has a different tree that is at least as up to date as this one
-        // this needs to be fixed later, reading from the disk is slow
-        let tree_path = &("trees".to_string() + "/" + &repo_name + ".tree").to_string();
-        self.trees.insert(repo_name.clone(), Tree::load_from_file(tree_path));
-
-        if let Some(tree) = self.trees.get(&repo_name) {
-            println!("retrieving updates from {} to {}", client_version, tree.version);
-            if tree.version > client_version {
-                let updates = tree.history.iter()
-                    .filter(|(v, _)| {
-                        *v >= &client_version
-                        })
-                    .map(|(&v, entry)| (v, entry.clone()))
-                    .collect::<HashMap<i32, String>>();
-                
-                let response_body = serde_json::to_vec(&updates)?;
-                response = Response {
-                    status_code: ResponseCodes::OK,
-                    status_message: "Missing updates".to_string(),
-                    body: response_body,
-                };
-            }
-            else {
-                response = Response {
-                    status_code: ResponseCodes::OK,
-                    status_message: "No updates".to_string(),
-                    body: Vec::new(),
-                };
-            }
+        let tree_store = self.stores.tree_store.lock()
+            .map_err(|e| anyhow::anyhow!("tree store lock poisoned: {}", e))?;
+
+        if let Some(tree) = tree_store.get(&repo_name) {
+            // a client that is ahead of us or asks for one gets the whole tree instead of a diff
+            let sync = match tree.updates_since(client_version).filter(|_| !snapshot) {
+                Some(operations) => {
+                    println!("retrieving updates from {} to {}", client_version, tree.version);
+                    TreeSync::Updates { head_version: tree.version, operations }
+                }
+                None => {
+                    println!("sending a snapshot of {} at version {} to a client at {}", repo_name, tree.version, client_version);
+                    TreeSync::Snapshot(tree.clone())
+                }
+            };
+            let status_message = match &sync {
+                TreeSync::Updates { operations, .. } if operations.is_empty() => "No updates".to_string(),
+                TreeSync::Updates { operations, .. } => format!("{} updates", operations.len()),
+                TreeSync::Snapshot(_) => "Snapshot".to_string(),
+            };
+
+            response = Response {
+                status_code: ResponseCodes::OK,
+                status_message,
+                body: serde_json::to_vec(&sync)?,
+            };
         } else {
             response = Response {
                 status_code: ResponseCodes::NotFound,
@@ -133,26 +148,128 @@ impl PhotoServerRequestHandler {
             };
         }
 
+        drop(tree_store);
+
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn get_repo_stats(&mut self, request:Request) -> anyhow::Result<()> {
+        let repo_name = String::from_utf8_lossy(&request.body)
+            .trim()
+            .replace(|c: char| c.is_control(), "_")
+            .to_string();
+
+        let response = if !self.config.repo_list.contains(&repo_name) {
+            Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "Repo not found".to_string(),
+                body: format!("{} does not exist", repo_name).as_bytes().to_vec(),
+            }
+        } else {
+            let mut stats = self.stores.catalog.lock()
+                .map_err(|e| anyhow::anyhow!("catalog lock poisoned: {}", e))?
+                .repo_stats(&repo_name)?;
+            stats.quota = self.config.quota(&repo_name);
+            Response {
+                status_code: ResponseCodes::OK,
+                status_message: format!("{} files, {} bytes", stats.file_count, stats.total_bytes),
+                body: serde_json::to_vec(&stats)?,
+            }
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn set_repo_quota(&mut self, request:Request) -> anyhow::Result<()> {
+        let update: RepoQuotaUpdate = serde_json::from_slice(&request.body)?;
+
+        let response = if !self.config.repo_list.contains(&update.repo_name) {
+            Response {
+                status_code: ResponseCodes::NotFound,
+                status_message: "Repo not found".to_string(),
+                body: format!("{} does not exist", update.repo_name).as_bytes().to_vec(),
+            }
+        } else {
+            // batch processors copy the config for every batch, so the new quota applies to the next one
+            self.update_config(|config| config.set_quota(&update.repo_name, update.quota))?;
+            let limits: Vec<String> = [
+                update.quota.max_files.map(|max_files| format!("{} files", max_files)),
+                update.quota.max_bytes.map(|max_bytes| format!("{} bytes", max_bytes)),
+            ].into_iter().flatten().collect();
+            let message = if limits.is_empty() {
+                format!("{} no longer has a quota", update.repo_name)
+            } else {
+                format!("{} may now hold {}", update.repo_name, limits.join(" and "))
+            };
+            Response {
+                status_code: ResponseCodes::OK,
+                status_message: "OK".to_string(),
+                body: message.as_bytes().to_vec(),
+            }
+        };
+        send_response(response, &mut self.stream)?;
+        Ok(())
+    }
+
+    pub fn search(&mut self, request:Request) -> anyhow::Result<()> {
+        let query: SearchQuery = serde_json::from_slice(&request.body)?;
+
+        // only repositories the user can read are searched, naming one they can't read is refused outright
+        let readable_repositories: Vec<String> = self.config.repo_list.iter()
+            .filter(|repo| self.config.role(repo, &self.user).is_some())
+            .filter(|repo| query.repo_name.as_ref().is_none_or(|repo_name| repo_name == *repo))
+            .cloned()
+            .collect();
+        if let Some(repo_name) = query.repo_name.as_ref().filter(|_| readable_repositories.is_empty()) {
+            return self.deny(format!("{} has no access to {}", self.user, repo_name));
+        }
+
+        let results = self.stores.catalog.lock()
+            .map_err(|e| anyhow::anyhow!("catalog lock poisoned: {}", e))?
+            .search(&query, &readable_repositories)?;
+
+        let response = Response {
+            status_code: if results.total == 0 { ResponseCodes::Empty } else { ResponseCodes::OK },
+            status_message: format!("{} files found", results.total),
+            body: serde_json::to_vec(&results)?,
+        };
         send_response(response, &mut self.stream)?;
         Ok(())
     }
 
-    pub fn start_batch_processor(&mut self) -> anyhow::Result<()> {    
-            let port = rand::rng().random_range(0..u16::MAX);
-            let file_stream_address = format!("0.0.0.0:{}", port);
+    pub fn start_batch_processor(&mut self, request:Request) -> anyhow::Result<()> {    
+            // the file stream arrives on the shared data port, the token decides which batch processor gets it
+            self.close_batch_session();
+            let session_token = new_nonce();
+            let (file_stream_tx, file_stream_rx) = mpsc::channel();
+            self.sessions.lock()
+                .map_err(|e| anyhow::anyhow!("session registry lock poisoned: {}", e))?
+                .insert(session_token.clone(), file_stream_tx);
+            self.batch_session = Some(session_token.clone());
+
+            // agree on the codecs both sides support, keeping the client's order of preference
+            let client_codecs: Vec<Codec> = serde_json::from_slice(&request.body).unwrap_or_default();
+            let codecs: Vec<Codec> = client_codecs.into_iter()
+                .filter(|codec| Codec::supported().contains(codec))
+                .collect();
 
-            let listener = TcpListener::bind(&file_stream_address)?;
+            let offer = BatchProcessorOffer {
+                port: self.config.data_port,
+                session_token,
+                codecs: codecs.clone(),
+            };
             
             let response = Response {
                 status_code:ResponseCodes::OK,
-                status_message: format!("Initiated file stream @ {}", &file_stream_address).to_string(),
-                body: file_stream_address.as_bytes().to_vec(),
+                status_message: format!("Initiated file stream on port {} | codecs {:?}", self.config.data_port, &codecs).to_string(),
+                body: serde_json::to_vec(&offer)?,
             };
 
             send_response(response, &mut self.stream)?;
 
             let stop_flag = Arc::new(atomic::AtomicBool::new(false));
-            match initiate_batch_processor(PathBuf::from(&self.config.storage_directory), listener,stop_flag.clone()) {
+            match initiate_batch_processor(self.config.clone(), file_stream_rx, stop_flag.clone(), self.stores.clone(), codecs, self.user.clone(), self.device.clone()) {
                 
                 Ok(handle) => { 
                     self.batch_processor_context = Some((handle, stop_flag))
@@ -173,6 +290,7 @@ impl PhotoServerRequestHandler {
     pub fn end_batch_processor(&mut self) -> anyhow::Result<()> {           
         if let Some((handle, stop_flag)) = self.batch_processor_context.take() {
             stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            self.close_batch_session();
             let response:Response;
             if let Err(_e) = &handle.join() {
                 response = Response { 
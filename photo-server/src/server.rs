@@ -1,18 +1,59 @@
-use std::net::TcpListener;
+use std::{collections::HashMap, net::TcpListener, sync::{Arc, Mutex}};
 
-use shared::{send_response, Response, ResponseCodes};
+use shared::{send_response, auth::Identity, tls::{self, ServerTlsConfig}, Response, ResponseCodes, Stream};
 use crate::request_handler::PhotoServerRequestHandler;
+use crate::request_handler::request_handler_utils::{ServerConfig, SharedConfig};
+use crate::object_store::ObjectStore;
+use crate::tree_store::TreeStore;
+use crate::catalog::Catalog;
+use crate::thumbnails::Thumbnails;
+use crate::authentication::{authenticate_device, EnrollmentLimiter};
+use crate::filestreamserver::{run_data_listener, SessionRegistry};
+
+// the state every request handler and batch processor shares, locked in field order when more than one is needed
+#[derive(Clone)]
+pub struct Stores {
+    pub config: SharedConfig, // copied out for every request, only locked on its own to read or change it
+    pub tree_store: Arc<Mutex<TreeStore>>, // one version counter per repository
+    pub object_store: Arc<Mutex<ObjectStore>>,
+    pub catalog: Arc<Mutex<Catalog>>,
+    pub thumbnails: Thumbnails, // not locked, the worker owns the cache
+}
+
+impl Stores {
+    pub fn new(config: ServerConfig, tree_store: TreeStore, object_store: ObjectStore, catalog: Catalog, thumbnails: Thumbnails) -> Self {
+        Stores {
+            config: Arc::new(Mutex::new(config)),
+            tree_store: Arc::new(Mutex::new(tree_store)),
+            object_store: Arc::new(Mutex::new(object_store)),
+            catalog: Arc::new(Mutex::new(catalog)),
+            thumbnails,
+        }
+    }
+}
 
 pub struct PhotoServer {
     pub name: String,
     pub address: String,
+    pub stores: Stores,
+    pub tls_config: Option<ServerTlsConfig>,
+    pub identity: Arc<Identity>,
+    pub data_port: u16,
+    pub sessions: SessionRegistry, // batch processors waiting on the data port
+    pub enrollment_limiter: EnrollmentLimiter,
 }
 
 impl PhotoServer {
-    pub fn new(name: String, address: String,) -> Self {
+    pub fn new(name: String, address: String, stores: Stores, tls_config: Option<ServerTlsConfig>, identity: Arc<Identity>, data_port: u16) -> Self {
         PhotoServer {
             name,
             address,
+            stores,
+            tls_config,
+            identity,
+            data_port,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            enrollment_limiter: EnrollmentLimiter::default(),
         }
     }
 
@@ -23,29 +64,71 @@ impl PhotoServer {
         if std::path::Path::new("photo-server/trees").exists() == false {
             std::fs::create_dir_all("trees")?;
         }
+        run_data_listener(self.data_port, self.sessions.clone(), self.tls_config.clone())?;
 
         for stream in listener.incoming() {
-            let mut stream = match stream {
+            let stream = match stream {
                 Ok(s) => s,
                 Err(e) => return Err(e)
             };
 
             println!("New connection: {}", stream.peer_addr().expect("Failed to get peer address"));
 
-            let response = Response {
-                status_code: ResponseCodes::OK,
-                status_message: "OK".to_string(),
-                body: format!("connected to photo server @ {}", self.name).as_bytes().to_vec(),
-            };
-
-            send_response(response, &mut stream)?;
+            let name = self.name.clone();
+            let stores = self.stores.clone();
+            let tls_config = self.tls_config.clone();
+            let identity = self.identity.clone();
+            let sessions = self.sessions.clone();
+            let enrollment_limiter = self.enrollment_limiter.clone();
             
             // spawn a request handler in a seperate thread so we can accept another connection
             let _ = std::thread::spawn(move || {
-                let mut request_handler = PhotoServerRequestHandler::new(
-                    "./photo-server-config.json".to_string(),
-                    stream);
-                
+                // the tls handshake happens here so a slow client can't hold up the listener
+                let mut stream = match &tls_config {
+                    Some(tls_config) => match tls::accept(stream, tls_config.clone()) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            println!("tls handshake failed. {}", e);
+                            return;
+                        }
+                    },
+                    None => Stream::Plain(stream),
+                };
+
+                let device = match authenticate_device(&mut stream, &identity, &stores.config, &enrollment_limiter) {
+                    Ok(Some(device)) => device,
+                    Ok(None) => return,
+                    Err(e) => {
+                        println!("authentication failed. {}", e);
+                        return;
+                    }
+                };
+                println!("{} authenticated as {}", device.name, device.user_name());
+
+                let response = Response {
+                    status_code: ResponseCodes::OK,
+                    status_message: "OK".to_string(),
+                    body: format!("connected to photo server @ {}", name).as_bytes().to_vec(),
+                };
+
+                if let Err(e) = send_response(response, &mut stream) {
+                    println!("{}", e);
+                    return;
+                }
+
+                let mut request_handler = match PhotoServerRequestHandler::new(
+                    stream,
+                    device.user_name().to_string(),
+                    device.name.clone(),
+                    stores,
+                    sessions) {
+                    Ok(request_handler) => request_handler,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+
                 if let Err(e) = request_handler.run() {
                     println!("{}", e);
                 }
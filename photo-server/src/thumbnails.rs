@@ -0,0 +1,120 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::mpsc};
+use image::{metadata::Orientation, ImageFormat};
+use crate::media_metadata::read_orientation;
+
+// the longest edge of each thumbnail kept for an image, a small one for grids and a large one for previews
+pub const THUMBNAIL_SIZES: [u32; 2] = [256, 1024];
+
+struct ThumbnailJob {
+    hash: String,
+    stored_path: PathBuf,
+}
+
+// thumbnails are cached by content hash outside the storage directory, so renamed and deduplicated files share them
+// and the repository folders keep mirroring the clients' watch directories
+#[derive(Clone)]
+pub struct Thumbnails {
+    directory: PathBuf,
+    queue: mpsc::Sender<ThumbnailJob>,
+}
+
+impl Thumbnails {
+    // creates the cache directory and starts the worker that fills it
+    pub fn start(directory: &str) -> std::io::Result<Self> {
+        let directory = PathBuf::from(directory);
+        std::fs::create_dir_all(&directory)?;
+        let (queue, jobs) = mpsc::channel::<ThumbnailJob>();
+
+        let worker_directory = directory.clone();
+        std::thread::spawn(move || {
+            for job in jobs {
+                if let Err(e) = generate(&worker_directory, &job.hash, &job.stored_path) {
+                    println!("Unable to generate thumbnails for {}. {}", job.stored_path.to_string_lossy(), e);
+                }
+            }
+        });
+        Ok(Thumbnails { directory, queue })
+    }
+
+    // thumbnails are made in the background so uploads don't wait on decoding
+    pub fn queue(&self, hash: &str, stored_path: &Path) {
+        let job = ThumbnailJob {
+            hash: hash.to_string(),
+            stored_path: stored_path.to_path_buf(),
+        };
+        if self.queue.send(job).is_err() {
+            println!("The thumbnail worker has stopped, {} will get its thumbnails when it is requested", stored_path.to_string_lossy());
+        }
+    }
+
+    // the jpeg for the smallest size that covers the requested one, made on the spot if the worker hasn't got to it yet
+    pub fn get(&self, hash: &str, stored_path: &Path, requested_size: u32) -> anyhow::Result<(u32, Vec<u8>)> {
+        let size = THUMBNAIL_SIZES.iter()
+            .copied()
+            .find(|size| *size >= requested_size)
+            .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1]);
+        let path = thumbnail_path(&self.directory, hash, size);
+        if !path.exists() {
+            generate(&self.directory, hash, stored_path)?;
+        }
+        Ok((size, std::fs::read(path)?))
+    }
+
+    // drops thumbnails for content no repository holds anymore, returns how many were removed
+    pub fn prune(&self, hashes: &HashSet<String>) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for prefix in std::fs::read_dir(&self.directory)? {
+            let prefix = prefix?.path();
+            if !prefix.is_dir() {
+                continue;
+            }
+            for thumbnail in std::fs::read_dir(&prefix)? {
+                let thumbnail = thumbnail?.path();
+                let hash = thumbnail.file_name()
+                    .and_then(|s| s.to_str())
+                    .and_then(|file_name| file_name.split('-').next())
+                    .unwrap_or("");
+                if !hashes.contains(hash) {
+                    std::fs::remove_file(&thumbnail)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn thumbnail_path(directory: &Path, hash: &str, size: u32) -> PathBuf {
+    directory.join(&hash[..2]).join(format!("{}-{}.jpg", hash, size))
+}
+
+// writes every size that is missing, the image is decoded once and turned upright before it is scaled
+fn generate(directory: &Path, hash: &str, stored_path: &Path) -> anyhow::Result<()> {
+    if hash.len() < 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("{} is not a content hash", hash));
+    }
+    let missing: Vec<u32> = THUMBNAIL_SIZES.iter()
+        .copied()
+        .filter(|size| !thumbnail_path(directory, hash, *size).exists())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut image = image::open(stored_path)?;
+    if let Some(orientation) = read_orientation(stored_path).and_then(|o| Orientation::from_exif(o as u8)) {
+        image.apply_orientation(orientation);
+    }
+
+    for size in missing {
+        let path = thumbnail_path(directory, hash, size);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // the worker and a request can race on the same thumbnail, each writes its own file and the rename settles it
+        let temporary_path = path.with_extension(format!("{:?}.tmp", std::thread::current().id()));
+        image.thumbnail(size, size).to_rgb8().save_with_format(&temporary_path, ImageFormat::Jpeg)?;
+        std::fs::rename(&temporary_path, &path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, path::Path, time::SystemTime};
+use serde::Deserialize;
+use shared::{hash_bytes, FileEntry, Tree, TreeOperation};
+use shared::tree::sanitize_relative_path;
+
+// trees written before they were keyed by relative path, history entries are "+<absolute client path>"
+#[derive(Deserialize)]
+struct LegacyTree {
+    version: i32,
+    history: HashMap<i32, String>,
+    path: String,
+    name: String,
+    #[serde(default)]
+    stored_paths: HashMap<String, String>,
+}
+
+impl LegacyTree {
+    // replays the old history in version order so every file keeps the version it was given
+    fn migrate(self, storage_directory:&Path) -> Tree {
+        let mut tree = Tree {
+            path: self.path.clone(),
+            name: self.name.clone(),
+            ..Default::default()
+        };
+
+        for version in 0..self.version {
+            let file_location = match self.history.get(&version) {
+                Some(entry) => entry.trim_start_matches('+').to_string(),
+                None => continue,
+            };
+            // files stored before the client's hierarchy was mirrored sit at the top of the repository
+            let stored_path = self.stored_paths.get(&file_location).cloned()
+                .unwrap_or_else(|| file_location.rsplit('/').next().unwrap_or(&file_location).to_string());
+            let relative_path = match sanitize_relative_path(&stored_path) {
+                Some(relative_path) => relative_path,
+                None => continue,
+            };
+
+            let file_path = storage_directory.join(&self.name).join(&relative_path);
+            let data = std::fs::read(&file_path).unwrap_or_default();
+            let modified = std::fs::metadata(&file_path)
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+
+            let entry = FileEntry {
+                size: data.len() as u64,
+                hash: hash_bytes(&data),
+                created: modified,
+                uploaded: modified,
+                file_location,
+            };
+            tree.add_history(TreeOperation::Add { path: relative_path, entry });
+        }
+        tree.apply_history(0);
+        tree
+    }
+}
+
+// rewrites every tree in the old directory name keyed format, keeping the original next to it as .legacy
+pub fn migrate_trees(storage_directory:&Path) -> std::io::Result<()> {
+    if !Path::new("trees").exists() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir("trees")? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("tree") {
+            continue;
+        }
+
+        let tree_content = std::fs::read_to_string(&path)?;
+        if serde_json::from_str::<Tree>(&tree_content).is_ok() {
+            continue;
+        }
+        let legacy_tree = match serde_json::from_str::<LegacyTree>(&tree_content) {
+            Ok(legacy_tree) => legacy_tree,
+            Err(e) => {
+                println!("Unable to read {} in either tree format. {}", path.to_string_lossy(), e);
+                continue;
+            }
+        };
+
+        std::fs::copy(&path, path.with_extension("tree.legacy"))?;
+        let tree = legacy_tree.migrate(storage_directory);
+        tree.save_to_file(path.to_string_lossy().as_ref());
+        println!("Migrated {} to a path keyed tree with {} files", path.to_string_lossy(), tree.files.len());
+    }
+    Ok(())
+}
+
+// folds whatever the journals hold into fresh snapshots so nothing is appended after a torn record
+// fails on the first tree that can't be read, the server shouldn't start without it
+pub fn recover_trees() -> std::io::Result<()> {
+    if !Path::new("trees").exists() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir("trees")? {
+        let path = entry?.path();
+        let path_name = path.to_string_lossy().to_string();
+        // a snapshot that was being written when the server stopped, the previous one is still in place
+        if path_name.ends_with(".tree.tmp") {
+            std::fs::remove_file(&path)?;
+            continue;
+        }
+        if path.extension().and_then(|e| e.to_str()) != Some("tree") {
+            continue;
+        }
+
+        // a corrupt snapshot stops the server, rewriting it would also clear the journal that still holds its operations
+        let (tree, replay) = Tree::load_with_journal(&path_name)?;
+        if replay.replayed == 0 && replay.truncated == 0 {
+            continue;
+        }
+        println!("Recovered {} at version {}, replayed {} journal records and dropped {} truncated ones",
+            path_name, tree.version, replay.replayed, replay.truncated);
+        tree.save_to_file(&path_name);
+    }
+    Ok(())
+}
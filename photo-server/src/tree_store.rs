@@ -0,0 +1,80 @@
+use std::{collections::HashMap, path::Path};
+use shared::{Tree, TreeOperation};
+
+// every repository's tree, shared by the request handlers and batch processors so each repository
+// has a single version counter no matter how many clients are uploading to it
+#[derive(Default, Debug)]
+pub struct TreeStore {
+    trees: HashMap<String, Tree>, // keyed by repository name
+}
+
+impl TreeStore {
+    // reads every tree in directory, journals should already have been recovered
+    pub fn load(directory: &str) -> std::io::Result<Self> {
+        let mut tree_store = TreeStore::default();
+        if !Path::new(directory).exists() {
+            return Ok(tree_store);
+        }
+
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("tree") {
+                continue;
+            }
+            let repo_name = match path.file_stem() {
+                Some(file_stem) => file_stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let (tree, _) = Tree::load_with_journal(path.to_string_lossy().as_ref())?;
+            tree_store.trees.insert(repo_name, tree);
+        }
+        Ok(tree_store)
+    }
+
+    pub fn get(&self, repo_name: &str) -> Option<&Tree> {
+        self.trees.get(repo_name)
+    }
+
+    // each repository's name along with its tree
+    pub fn trees(&self) -> impl Iterator<Item = (&String, &Tree)> {
+        self.trees.iter()
+    }
+
+    // the number of files tracked across every repository
+    pub fn file_count(&self) -> u64 {
+        self.trees.values().map(|tree| tree.files.len() as u64).sum()
+    }
+
+    // the repository's tree, starting an empty one if it has never been written
+    pub fn tree_mut(&mut self, repo_name: &str) -> &mut Tree {
+        self.trees.entry(repo_name.to_string()).or_insert_with(|| Tree::new(repo_name))
+    }
+
+    pub fn create(&mut self, repo_name: &str) {
+        let tree = Tree::new(repo_name);
+        tree.save_to_file(&tree.path);
+        self.trees.insert(repo_name.to_string(), tree);
+    }
+
+    pub fn remove(&mut self, repo_name: &str) -> std::io::Result<()> {
+        if let Some(tree) = self.trees.remove(repo_name)
+            && Path::new(&tree.path).exists() {
+            Tree::delete_files(&tree.path)?;
+        }
+        Ok(())
+    }
+
+    // appends the operation to the repository's history and journal and applies it, returning the version it was given
+    // the files only change once the journal holds the operation, so clients are never handed a version a restart would lose
+    pub fn record(&mut self, repo_name: &str, operation: TreeOperation) -> anyhow::Result<i32> {
+        let tree = self.tree_mut(repo_name);
+        let start_index = tree.add_history(operation);
+        if let Err(e) = tree.persist(start_index) {
+            tree.history.remove(&start_index);
+            tree.version = start_index;
+            return Err(anyhow::anyhow!("unable to journal version {} of {}. {}", start_index, repo_name, e));
+        }
+        tree.apply_history(start_index);
+        Ok(start_index)
+    }
+}
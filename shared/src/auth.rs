@@ -0,0 +1,133 @@
+use std::io::{Read, Write};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use anyhow::Result;
+
+use crate::{read_message, send_message};
+
+// the long lived key pair a device or the server proves itself with
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    // the private key is kept hex encoded next to the config
+    pub fn load_or_generate(path: &str) -> Result<Self> {
+        if let Ok(key_content) = std::fs::read_to_string(path) {
+            let key_bytes: [u8; 32] = hex::decode(key_content.trim())?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("{} does not hold a 32 byte key", path))?;
+            return Ok(Identity { signing_key: SigningKey::from_bytes(&key_bytes) });
+        }
+
+        println!("Identity not found, generating a new key pair at {}", path);
+        let identity = Identity { signing_key: SigningKey::from_bytes(&rand::random::<[u8; 32]>()) };
+        write_private_file(path, hex::encode(identity.signing_key.to_bytes()).as_bytes())?;
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.signing_key.sign(message).to_bytes())
+    }
+}
+
+// private keys are only readable by the user running the client or server
+pub fn write_private_file(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // a file that already existed keeps its old mode through open
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> Result<()> {
+    let public_key_bytes: [u8; 32] = hex::decode(public_key)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("public key is not 32 bytes"))?;
+    let signature_bytes: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("signature is not 64 bytes"))?;
+
+    VerifyingKey::from_bytes(&public_key_bytes)?
+        .verify(message, &Signature::from_bytes(&signature_bytes))?;
+    Ok(())
+}
+
+pub fn new_nonce() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+// each side signs both nonces with its own label so a signature can't be replayed in the other direction
+pub fn server_transcript(client_nonce: &str, server_nonce: &str) -> Vec<u8> {
+    format!("photo-server:{}:{}", client_nonce, server_nonce).into_bytes()
+}
+
+pub fn client_transcript(client_nonce: &str, server_nonce: &str) -> Vec<u8> {
+    format!("photo-client:{}:{}", server_nonce, client_nonce).into_bytes()
+}
+
+// client -> server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthHello {
+    pub device_name: String,
+    pub user_name: String, // the account a device is enrolled under, ignored once it is enrolled
+    pub public_key: String,
+    pub nonce: String,
+    pub enrollment_code: String, // only needed the first time a device connects
+}
+
+// server -> client, the signature proves the server holds its private key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthChallenge {
+    pub public_key: String,
+    pub nonce: String,
+    pub signature: String,
+}
+
+// client -> server, the signature proves the device holds its private key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthProof {
+    pub signature: String,
+}
+
+// the device side of the handshake, returns the server's public key once it has proven it owns it
+pub fn authenticate_with_server<S: Read + Write>(
+    stream: &mut S,
+    identity: &Identity,
+    device_name: &str,
+    user_name: &str,
+    enrollment_code: &str,
+    pinned_server_key: Option<&String>,
+) -> Result<String> {
+    let hello = AuthHello {
+        device_name: device_name.to_string(),
+        user_name: user_name.to_string(),
+        public_key: identity.public_key(),
+        nonce: new_nonce(),
+        enrollment_code: enrollment_code.to_string(),
+    };
+    send_message(&hello, stream)?;
+
+    let challenge: AuthChallenge = read_message(stream)?;
+    if let Some(pinned_server_key) = pinned_server_key
+        && pinned_server_key != &challenge.public_key {
+        return Err(anyhow::anyhow!("server key {} does not match the pinned key {}", challenge.public_key, pinned_server_key));
+    }
+    verify(&challenge.public_key, &server_transcript(&hello.nonce, &challenge.nonce), &challenge.signature)
+        .map_err(|e| anyhow::anyhow!("server failed to prove its identity: {}", e))?;
+
+    let proof = AuthProof {
+        signature: identity.sign(&client_transcript(&hello.nonce, &challenge.nonce)),
+    };
+    send_message(&proof, stream)?;
+    Ok(challenge.public_key)
+}
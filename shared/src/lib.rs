@@ -1,11 +1,16 @@
-use std::net::TcpStream;
 use std::io::Read;
 use std::io::Write;
 use bincode::{Decode, Encode};
 use serde::Deserialize;
 use serde::Serialize;
 use anyhow::Result;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+
+pub mod tls;
+pub mod auth;
+pub mod tree;
+pub use tls::Stream;
+pub use tree::{FileEntry, Tree, TreeChange, TreeOperation, TreeSync, VersionedOperation};
 
 #[derive(Debug, Encode, Decode)]
 pub struct FileHeader {
@@ -13,8 +18,113 @@ pub struct FileHeader {
     pub file_name: String,
     pub file_size: usize,
     pub file_location: String,
+    pub relative_path: String, // where the file sits below the repo's watch directory, eg. DCIM/2024/IMG_0001.jpg
     pub file_ext: String,
     pub file_datetime: std::time::SystemTime,
+    pub file_hash: String, // hex encoded sha256 of the file contents
+    pub upload_id: String, // identifies a partial upload so it can be resumed after a dropped connection
+    pub codec: Codec, // how each chunk of this file is compressed on the wire
+}
+
+#[derive(Serialize, Deserialize, Encode, Decode, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    // in order of preference
+    pub fn supported() -> Vec<Codec> {
+        vec![Codec::Zstd, Codec::Lz4]
+    }
+
+    pub fn compress(&self, data:&[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => Ok(zstd::bulk::compress(data, 3)?),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    // every chunk is at most CHUNK_SIZE once decompressed, so a chunk that would inflate past it is refused
+    // rather than letting a few compressed bytes fill the receiver's memory
+    pub fn decompress(&self, data:&[u8]) -> Result<Vec<u8>> {
+        let chunk = match self {
+            Codec::None => data.to_vec(),
+            Codec::Zstd => {
+                let mut chunk = Vec::new();
+                zstd::stream::read::Decoder::new(data)?.take(CHUNK_SIZE as u64 + 1).read_to_end(&mut chunk)?;
+                chunk
+            }
+            Codec::Lz4 => {
+                // lz4 allocates whatever size the chunk says it holds before it decompresses anything
+                let size = data.get(..4)
+                    .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+                    .ok_or_else(|| anyhow::anyhow!("lz4 chunk is missing its size"))?;
+                if size > CHUNK_SIZE {
+                    return Err(anyhow::anyhow!("lz4 chunk claims {} bytes, over the {} byte chunk size", size, CHUNK_SIZE));
+                }
+                lz4_flex::decompress_size_prepended(data)?
+            }
+        };
+        if chunk.len() > CHUNK_SIZE {
+            return Err(anyhow::anyhow!("chunk decompresses past the {} byte chunk size", CHUNK_SIZE));
+        }
+        Ok(chunk)
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Zstd => write!(f, "zstd"),
+            Codec::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+// sent back when StartBatchProcessor is accepted, the codecs are the ones both sides support
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct BatchProcessorOffer {
+    pub port: u16, // the server's data port, on the same host as the command connection
+    pub session_token: String, // presented on the data port so only this client can claim the batch processor
+    pub codecs: Vec<Codec>,
+}
+
+// client -> server, the first message on every data port connection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataSessionHello {
+    pub session_token: String,
+}
+
+const IMAGE_EXTENSIONS: [&str; 11] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff", "heic", "heif", "avif"];
+const VIDEO_EXTENSIONS: [&str; 7] = ["mp4", "mov", "mkv", "avi", "m4v", "3gp", "webm"];
+
+// image, video or other, going by the extension
+pub fn media_type(relative_path: &str) -> &'static str {
+    let file_ext = std::path::Path::new(relative_path).extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    if IMAGE_EXTENSIONS.contains(&file_ext.as_str()) {
+        "image"
+    } else if VIDEO_EXTENSIONS.contains(&file_ext.as_str()) {
+        "video"
+    } else {
+        "other"
+    }
+}
+
+pub fn hash_bytes(data:&[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// the same file sent to the same place always gets the same upload id, so a resend picks up where the last one stopped
+pub fn upload_id(repo_name:&str, file_location:&str, file_hash:&str) -> String {
+    hash_bytes(format!("{}:{}:{}", repo_name, file_location, file_hash).as_bytes())
 }
 
 #[derive(Decode, Encode)]
@@ -23,9 +133,26 @@ pub struct Job {
     pub data:Vec<u8>
 }
 
+impl Job {
+    // checks the received payload against what the sender said it sent
+    pub fn verify(&self) -> Result<(), String> {
+        if self.data.len() != self.file_header.file_size {
+            return Err(format!("{} size mismatch: expected {} bytes, received {}",
+                self.file_header.file_name, self.file_header.file_size, self.data.len()));
+        }
+        let received_hash = hash_bytes(&self.data);
+        if received_hash != self.file_header.file_hash {
+            return Err(format!("{} hash mismatch: expected {}, received {}",
+                self.file_header.file_name, self.file_header.file_hash, received_hash));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct BatchJob {
     pub jobs:Vec<Job>,
+    pub changes:Vec<TreeChange>, // removals and renames, applied after the uploads
     pub max_batch_size: usize,
 }
 
@@ -33,13 +160,21 @@ impl BatchJob {
     pub fn new(jobs:Vec<Job>) -> Self {
         Self {
             jobs: jobs,
+            changes: Vec::new(),
             max_batch_size: 128,
         }
     }
+
+    pub fn from_changes(changes:Vec<TreeChange>) -> Self {
+        Self {
+            changes,
+            ..Self::new(Vec::new())
+        }
+    }
 }
 
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub enum ResponseCodes {
     OK,
     NotFound,
@@ -47,6 +182,9 @@ pub enum ResponseCodes {
     NotConnected,
     InternalError,
     Duplicate,
+    IntegrityError,
+    Unauthorized,
+    QuotaExceeded,
 }
 
 impl std::fmt::Display for ResponseCodes {
@@ -58,6 +196,9 @@ impl std::fmt::Display for ResponseCodes {
             ResponseCodes::NotConnected => write!(f,"Not Connected"),
             ResponseCodes::InternalError => write!(f, "Internal Server Error"),
             ResponseCodes::Duplicate => write!(f, "Duplicate"),
+            ResponseCodes::IntegrityError => write!(f, "Integrity Error"),
+            ResponseCodes::Unauthorized => write!(f, "Unauthorized"),
+            ResponseCodes::QuotaExceeded => write!(f, "Quota Exceeded"),
         }
     }
 }
@@ -69,6 +210,35 @@ pub struct Response {
     pub body: Vec<u8>,
 }
 
+// what happened to one file of a batch, the batch acknowledgement carries one per file in upload order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileResult {
+    pub file_location: String,
+    pub status_code: ResponseCodes,
+    pub stored_path: String,
+    pub bytes_written: u64, // 0 when the content was already stored
+    pub tree_version: Option<i32>,
+    pub message: String,
+}
+
+impl FileResult {
+    pub fn failed(file_location: &str, status_code: ResponseCodes, message: String) -> Self {
+        FileResult {
+            file_location: file_location.to_string(),
+            status_code,
+            stored_path: String::new(),
+            bytes_written: 0,
+            tree_version: None,
+            message,
+        }
+    }
+
+    // integrity and storage failures may go through on another attempt, a missing role or a full repository won't
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.status_code, ResponseCodes::IntegrityError | ResponseCodes::InternalError)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum RequestTypes {
     CreateRepo,
@@ -78,6 +248,73 @@ pub enum RequestTypes {
     RemoveRepository,
     GetRepoTree,
     StartBatchProcessor,
+    DownloadFile,
+    RestoreRepository,
+    GetStoreStats,
+    SetRepoAccess,
+    GetFileMetadata,
+    GetThumbnail,
+    Search,
+    GetRepoStats,
+    SetRepoQuota,
+    GetPendingDevices,
+    ApproveDevice,
+}
+
+// what a user may do in a repository, each role includes everything the ones before it can do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Reader,
+    Writer,
+    Owner,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Reader => write!(f, "Reader"),
+            Role::Writer => write!(f, "Writer"),
+            Role::Owner => write!(f, "Owner"),
+        }
+    }
+}
+
+// grants a role to a user, or revokes their access when role is None
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RepoAccess {
+    pub repo_name: String,
+    pub user_name: String,
+    pub role: Option<Role>,
+}
+
+// a device that presented the enrollment code under a user who already has devices, it can't connect
+// until one of that user's devices or an admin approves it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingDevice {
+    pub device_name: String,
+    pub user_name: String,
+    pub public_key: String,
+}
+
+// enrolls a pending device under the user it asked for, or turns it away when approve is false
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceApproval {
+    pub public_key: String,
+    pub approve: bool,
+}
+
+// how much a repository may hold, counting every tracked file at its full size even when its content is shared
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct RepoQuota {
+    pub max_bytes: Option<u64>, // None is unlimited
+    pub max_files: Option<u64>,
+}
+
+// replaces a repository's quota, a quota without limits removes it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RepoQuotaUpdate {
+    pub repo_name: String,
+    pub quota: RepoQuota,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -86,7 +323,104 @@ pub struct Request {
     pub body: Vec<u8>,
 }
 
-pub fn read_response(stream:&mut TcpStream) -> Result<Response,std::io::Error> {
+// how much space the server's content addressed store is using, and how much deduplication saved
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct StoreStats {
+    pub blob_count: u64,
+    pub reference_count: u64,
+    pub stored_bytes: u64,
+    pub logical_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+// what the server read from a stored file's exif, anything the file doesn't record is None
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct MediaMetadata {
+    pub captured: Option<i64>, // unix seconds, taken as utc when the camera didn't record its offset
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    pub exposure_time: Option<String>, // as the camera wrote it, eg. 1/250
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<f64>, // millimetres
+    pub orientation: Option<u32>, // exif orientation, 1 is upright
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>, // metres above sea level
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+// asks for a preview of a stored image, the server answers with the jpeg of the closest size it keeps
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThumbnailRequest {
+    pub repo_name: String,
+    pub path: String,
+    pub size: u32, // longest edge in pixels
+}
+
+// filters for the Search request, every filter that is set has to match
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SearchQuery {
+    pub repo_name: Option<String>, // None searches every repository the user can read
+    pub name: String, // part of the file name, or a glob like IMG_*.jpg when it has * or ?
+    pub extension: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub captured_after: Option<i64>, // unix seconds, files without a capture date go by their created time
+    pub captured_before: Option<i64>,
+    pub camera: Option<String>, // part of the camera make or model
+    pub page: u32,
+    pub page_size: u32,
+}
+
+// one page of matches, newest first
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SearchResults {
+    pub total: u64, // matches across every page
+    pub page: u32,
+    pub page_size: u32,
+    pub files: Vec<FileMetadata>,
+}
+
+// the files in one group of a repository's statistics, eg. every jpg or everything taken in 2024-06
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct StatsBucket {
+    pub label: String,
+    pub file_count: u64,
+    pub bytes: u64,
+}
+
+// what a repository holds according to the server's catalog
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct RepoStats {
+    pub repo_name: String,
+    pub file_count: u64,
+    pub total_bytes: u64,
+    pub last_upload: Option<i64>, // unix seconds
+    pub by_extension: Vec<StatsBucket>, // most files first
+    pub by_month: Vec<StatsBucket>, // YYYY-MM of the capture date, oldest first
+    pub devices: Vec<StatsBucket>, // the devices that uploaded the files, an empty label when it isn't known
+    #[serde(default)]
+    pub quota: RepoQuota,
+}
+
+// everything the server's catalog holds about one stored file, timestamps are unix seconds
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct FileMetadata {
+    pub repo_name: String,
+    pub relative_path: String,
+    pub size: u64,
+    pub hash: String,
+    pub uploaded: i64,
+    pub created: i64, // from the uploading device's filesystem, prefer media.captured when it is known
+    pub device: String,
+    pub media_type: String,
+    pub media: MediaMetadata,
+}
+
+pub fn read_response<S: Read>(stream:&mut S) -> Result<Response,std::io::Error> {
     let mut length_buffer = [0u8;4];
     stream.read_exact(&mut length_buffer)?;
 
@@ -96,85 +430,146 @@ pub fn read_response(stream:&mut TcpStream) -> Result<Response,std::io::Error> {
     Ok(response)
 }
 
-pub fn send_response(response: Response, stream:&mut TcpStream) -> Result<(), std::io::Error> {
+pub fn send_response<S: Write>(response: Response, stream:&mut S) -> Result<(), std::io::Error> {
     let ser_response = serde_json::to_vec(&response)?;
     stream.write_all(&(ser_response.len() as u32).to_be_bytes())?;
     stream.write_all(&ser_response)?;
     Ok(())
 }
 
-pub fn read_request(stream: &mut TcpStream) -> Result<Request, std::io::Error> {
+// requests, messages and file headers are read before the sender is trusted with anything, so their length is capped
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+fn read_frame<S: Read>(stream:&mut S, max_size:usize) -> Result<Vec<u8>, std::io::Error> {
     let mut length_buffer = [0u8; 4];
     stream.read_exact(&mut length_buffer)?;
+    let length = u32::from_be_bytes(length_buffer) as usize;
+    if length > max_size {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} bytes is over the {} byte limit", length, max_size)));
+    }
 
-    let mut request_buffer = vec![0u8; u32::from_be_bytes(length_buffer) as usize];
-    stream.read_exact(&mut request_buffer)?;
+    let mut buffer = vec![0u8; length];
+    stream.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+pub fn read_request<S: Read>(stream: &mut S) -> Result<Request, std::io::Error> {
+    let request_buffer = read_frame(stream, MAX_MESSAGE_SIZE)?;
     let request: Request = serde_json::from_slice(&request_buffer)?;
     Ok(request)
 }
 
-pub fn send_request(request:Request, stream:&mut TcpStream) -> Result<(), std::io::Error> {
+pub fn send_request<S: Write>(request:Request, stream:&mut S) -> Result<(), std::io::Error> {
     let ser_request = serde_json::to_vec(&request)?;
     stream.write_all(&(ser_request.len() as u32).to_be_bytes())?;
     stream.write_all(&ser_request)?;
     Ok(())
 }
 
+// any other json message, framed the same way as requests and responses
+pub fn send_message<T: Serialize, S: Write>(message:&T, stream:&mut S) -> Result<()> {
+    let ser_message = serde_json::to_vec(message)?;
+    stream.write_all(&(ser_message.len() as u32).to_be_bytes())?;
+    stream.write_all(&ser_message)?;
+    Ok(())
+}
 
-#[derive(Serialize,Deserialize, Default, Debug, Clone)]
-pub struct Tree {
-    pub version: i32,
-    pub content: HashMap<String,Vec<String>>, // a list of every directory's contents
-    pub history: HashMap<i32,String>, // a list of modifications
-    pub path: String,
-    pub name: String,
+pub fn read_message<T: serde::de::DeserializeOwned, S: Read>(stream:&mut S) -> Result<T> {
+    let message_buffer = read_frame(stream, MAX_MESSAGE_SIZE)?;
+    Ok(serde_json::from_slice(&message_buffer)?)
 }
 
-impl Tree {
-    pub fn load_from_file(path: &str) -> Self {
-        let tree_content = std::fs::read_to_string(path)
-        .unwrap_or_else(|_| {
-            println!("Tree file not found, using default tree.");
-            String::new()
-        });
-        serde_json::from_str(&tree_content).unwrap_or_else(|_| {
-            println!("Failed to parse tree file, using default tree.");
-            Tree::default()
-        })
-    }
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+// a compressed chunk of data that doesn't compress can come out a little larger than the chunk itself
+pub const MAX_WIRE_CHUNK_SIZE: usize = CHUNK_SIZE + CHUNK_SIZE / 8;
 
-    pub fn save_to_file(&self, path: &str) {
-        if let Ok(tree_content) = serde_json::to_string_pretty(self) {
-            if let Err(e) = std::fs::write(path, tree_content) {
-                eprintln!("Failed to write tree file: {}", e);
+// a job is framed as [header size][bincode header] followed by [chunk size][chunk]... and a 0 sized chunk
+pub fn send_job<S: Write>(job:&Job, stream:&mut S) -> Result<()> {
+    let header_bytes = bincode::encode_to_vec(&job.file_header, bincode::config::standard())?;
+    stream.write_all(&(header_bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&header_bytes)?;
 
-            }
-        }   
+    for chunk in job.data.chunks(CHUNK_SIZE) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+        stream.write_all(chunk)?;
     }
-    
-    pub fn apply_history(&mut self, start_index:i32) {
-        for i in start_index..=self.version{
-            if let Some(path_str) = self.history.get(&(i as i32)) {
-                let path: Vec<&str> =   path_str.split('/').skip(2).collect();
-                for window in path.windows(2) {
-                    let parent = window[0].to_string();
-                    let child = window[1].to_string();
-
-                    let entry = self.content.entry(parent)
-                        .or_insert_with(Vec::new);
-                    if !entry.contains(&child) {
-                        entry.push(child);
-                    }
-                }
-            }
+    stream.write_all(&0u32.to_be_bytes())?;
+    Ok(())
+}
+
+pub fn read_file_header<S: Read>(stream:&mut S) -> Result<FileHeader> {
+    let header_bytes = read_frame(stream, MAX_MESSAGE_SIZE)?;
+    let (file_header, _):(FileHeader, usize) = bincode::decode_from_slice(&header_bytes, bincode::config::standard())?;
+    Ok(file_header)
+}
+
+pub fn read_job<S: Read>(stream:&mut S) -> Result<Job> {
+    let file_header = read_file_header(stream)?;
+
+    // the header's size is only a hint, the data grows as the chunks actually arrive
+    let mut data = Vec::with_capacity(file_header.file_size.min(CHUNK_SIZE));
+    loop {
+        let mut chunk_size_buf = [0u8; 4];
+        stream.read_exact(&mut chunk_size_buf)?;
+        let chunk_size = u32::from_be_bytes(chunk_size_buf) as usize;
+        if chunk_size == 0 { break; }
+        if chunk_size > CHUNK_SIZE {
+            return Err(anyhow::anyhow!("{} byte chunk is over the {} byte chunk size", chunk_size, CHUNK_SIZE));
         }
+
+        let mut chunk = vec![0u8; chunk_size];
+        stream.read_exact(&mut chunk)?;
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Job { file_header, data })
+}
+
+
+// uploads are resumable, after the header the receiver answers with how many bytes of the upload it already holds
+// and every chunk carries the offset it starts at: [offset][chunk size][chunk]... ending with a 0 sized chunk
+// offsets count uncompressed bytes, each chunk is compressed on its own with the header's codec
+pub fn send_upload<S: Read + Write>(job:&Job, stream:&mut S) -> Result<()> {
+    let header_bytes = bincode::encode_to_vec(&job.file_header, bincode::config::standard())?;
+    stream.write_all(&(header_bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&header_bytes)?;
+
+    let mut committed_buf = [0u8; 8];
+    stream.read_exact(&mut committed_buf)?;
+    let committed = std::cmp::min(u64::from_be_bytes(committed_buf) as usize, job.data.len());
+
+    let mut offset = committed;
+    for chunk in job.data[committed..].chunks(CHUNK_SIZE) {
+        let wire_chunk = job.file_header.codec.compress(chunk)?;
+        stream.write_all(&(offset as u64).to_be_bytes())?;
+        stream.write_all(&(wire_chunk.len() as u32).to_be_bytes())?;
+        stream.write_all(&wire_chunk)?;
+        offset += chunk.len();
     }
+    stream.write_all(&(offset as u64).to_be_bytes())?;
+    stream.write_all(&0u32.to_be_bytes())?;
+    Ok(())
+}
 
-    pub fn add_history(&mut self, new_entry:String) -> i32 {
-        self.history.insert(self.version, new_entry);
-        self.version += 1;
-        self.version - 1
+// returns the chunk as it came off the wire, None once the sender has sent the 0 sized chunk
+pub fn read_upload_chunk<S: Read>(stream:&mut S) -> Result<Option<(u64, Vec<u8>)>> {
+    let mut offset_buf = [0u8; 8];
+    stream.read_exact(&mut offset_buf)?;
+    let offset = u64::from_be_bytes(offset_buf);
+
+    let mut chunk_size_buf = [0u8; 4];
+    stream.read_exact(&mut chunk_size_buf)?;
+    let chunk_size = u32::from_be_bytes(chunk_size_buf) as usize;
+    if chunk_size == 0 {
+        return Ok(None);
+    }
+    if chunk_size > MAX_WIRE_CHUNK_SIZE {
+        return Err(anyhow::anyhow!("{} byte chunk is over the {} byte limit", chunk_size, MAX_WIRE_CHUNK_SIZE));
     }
+
+    let mut chunk = vec![0u8; chunk_size];
+    stream.read_exact(&mut chunk)?;
+    Ok(Some((offset, chunk)))
 }
 
 #[derive(Serialize,Deserialize, Default, Debug, Clone)]
@@ -228,4 +623,158 @@ pub trait Log {
 
 pub trait Notify {
     fn notify_app(&self, response:&Response) -> anyhow::Result<()>;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // reads what the receiver would answer and keeps everything the sender writes
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn committed(committed: u64) -> Self {
+            Loopback { input: Cursor::new(committed.to_be_bytes().to_vec()), output: Vec::new() }
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn job(data: Vec<u8>, codec: Codec) -> Job {
+        Job {
+            file_header: FileHeader {
+                repo_name: "photos".to_string(),
+                file_name: "a.jpg".to_string(),
+                file_size: data.len(),
+                file_location: "/home/me/Pictures/a.jpg".to_string(),
+                relative_path: "a.jpg".to_string(),
+                file_ext: "jpg".to_string(),
+                file_datetime: std::time::SystemTime::UNIX_EPOCH,
+                file_hash: hash_bytes(&data),
+                upload_id: "00ff".to_string(),
+                codec,
+            },
+            data,
+        }
+    }
+
+    // compressible, but not so much that every chunk looks the same
+    fn sample(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect()
+    }
+
+    // everything read_upload_chunk hands back until the end of the upload
+    fn received_chunks(stream: &mut Cursor<Vec<u8>>) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = read_upload_chunk(stream)? {
+            chunks.push(chunk);
+        }
+        Ok(chunks)
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        let data = sample(CHUNK_SIZE);
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let compressed = codec.compress(&data).unwrap();
+            assert!(compressed.len() <= MAX_WIRE_CHUNK_SIZE, "{} chunk is too large for the wire", codec);
+            assert_eq!(codec.decompress(&compressed).unwrap(), data, "{} didn't round trip", codec);
+        }
+        assert!(Codec::Zstd.decompress(&Codec::Zstd.compress(&[]).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn chunks_that_inflate_past_the_chunk_size_are_refused() {
+        let bomb = vec![0u8; CHUNK_SIZE + 1];
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let compressed = codec.compress(&bomb).unwrap();
+            assert!(codec.decompress(&compressed).is_err(), "{} accepted an oversized chunk", codec);
+        }
+
+        // the size lz4 would allocate is checked before anything is decompressed
+        let mut lying = (u32::MAX).to_le_bytes().to_vec();
+        lying.extend_from_slice(&[0u8; 16]);
+        assert!(Codec::Lz4.decompress(&lying).is_err());
+        assert!(Codec::Lz4.decompress(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn uploads_are_framed_as_offset_chunks() {
+        let data = sample(CHUNK_SIZE * 2 + 10);
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let job = job(data.clone(), codec);
+            let mut sender = Loopback::committed(0);
+            send_upload(&job, &mut sender).unwrap();
+
+            let mut received = Cursor::new(sender.output);
+            let file_header = read_file_header(&mut received).unwrap();
+            assert_eq!(file_header.file_hash, job.file_header.file_hash);
+
+            let chunks = received_chunks(&mut received).unwrap();
+            assert_eq!(chunks.iter().map(|(offset, _)| *offset).collect::<Vec<u64>>(), [0, CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64]);
+            let reassembled: Vec<u8> = chunks.iter().flat_map(|(_, chunk)| codec.decompress(chunk).unwrap()).collect();
+            assert_eq!(reassembled, data);
+        }
+    }
+
+    #[test]
+    fn uploads_resume_after_the_committed_bytes() {
+        let data = sample(CHUNK_SIZE + 10);
+        let mut sender = Loopback::committed(CHUNK_SIZE as u64);
+        send_upload(&job(data.clone(), Codec::None), &mut sender).unwrap();
+
+        let mut received = Cursor::new(sender.output);
+        read_file_header(&mut received).unwrap();
+        let chunks = received_chunks(&mut received).unwrap();
+        assert_eq!(chunks, [(CHUNK_SIZE as u64, data[CHUNK_SIZE..].to_vec())]);
+    }
+
+    #[test]
+    fn truncated_uploads_are_an_error() {
+        let mut sender = Loopback::committed(0);
+        send_upload(&job(sample(1000), Codec::None), &mut sender).unwrap();
+
+        let mut received = Cursor::new(sender.output);
+        read_file_header(&mut received).unwrap();
+        let header_end = received.position() as usize;
+        let mut truncated = Cursor::new(received.into_inner()[header_end..header_end + 500].to_vec());
+        assert!(received_chunks(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn oversized_lengths_are_refused_before_they_are_allocated() {
+        let mut chunk = 0u64.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&(MAX_WIRE_CHUNK_SIZE as u32 + 1).to_be_bytes());
+        assert!(read_upload_chunk(&mut Cursor::new(chunk)).is_err());
+
+        let message = u32::MAX.to_be_bytes().to_vec();
+        assert!(read_message::<String, _>(&mut Cursor::new(message.clone())).is_err());
+        assert!(read_request(&mut Cursor::new(message.clone())).is_err());
+        assert!(read_file_header(&mut Cursor::new(message)).is_err());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut stream = Vec::new();
+        send_message(&vec!["a.jpg".to_string()], &mut stream).unwrap();
+        let message: Vec<String> = read_message(&mut Cursor::new(stream)).unwrap();
+        assert_eq!(message, ["a.jpg"]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{io::{Read, Write}, net::{Shutdown, SocketAddr, TcpStream}, sync::{Arc, Mutex}, time::Duration};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConnection, DigitallySignedStruct, ServerConnection, SignatureScheme, StreamOwned,
+};
+use anyhow::Result;
+
+use crate::hash_bytes;
+
+// either side of the command or file stream channel, with or without tls
+pub enum Stream {
+    Plain(TcpStream),
+    ServerTls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    ClientTls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::ServerTls(s) => &s.sock,
+            Stream::ClientTls(s) => &s.sock,
+        }
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub fn shutdown(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(_) => {},
+            Stream::ServerTls(s) => {
+                s.conn.send_close_notify();
+                s.flush().ok();
+            }
+            Stream::ClientTls(s) => {
+                s.conn.send_close_notify();
+                s.flush().ok();
+            }
+        }
+        self.tcp().shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::ServerTls(s) => s.read(buf),
+            Stream::ClientTls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::ServerTls(s) => s.write(buf),
+            Stream::ClientTls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::ServerTls(s) => s.flush(),
+            Stream::ClientTls(s) => s.flush(),
+        }
+    }
+}
+
+pub type ServerTlsConfig = Arc<rustls::ServerConfig>;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+pub fn certificate_fingerprint(certificate: &CertificateDer<'_>) -> String {
+    hash_bytes(certificate.as_ref())
+}
+
+pub fn load_server_config(certificate_path: &str, private_key_path: &str) -> Result<ServerTlsConfig> {
+    let certificates = CertificateDer::pem_file_iter(certificate_path)?
+        .collect::<Result<Vec<_>, _>>()?;
+    let private_key = PrivateKeyDer::from_pem_file(private_key_path)?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    Ok(Arc::new(config))
+}
+
+pub fn accept(tcp: TcpStream, config: ServerTlsConfig) -> Result<Stream> {
+    let mut tls = StreamOwned::new(ServerConnection::new(config)?, tcp);
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+    Ok(Stream::ServerTls(Box::new(tls)))
+}
+
+// trust on first use, any certificate is accepted until one has been pinned, after that only the pinned one is
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    provider: Arc<CryptoProvider>,
+    pinned_fingerprint: Option<String>,
+    seen_fingerprint: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = certificate_fingerprint(end_entity);
+        if let Some(pinned_fingerprint) = &self.pinned_fingerprint
+            && pinned_fingerprint != &fingerprint {
+            return Err(rustls::Error::General(format!(
+                "server certificate {} does not match the pinned certificate {}", fingerprint, pinned_fingerprint)));
+        }
+        if let Ok(mut seen_fingerprint) = self.seen_fingerprint.lock() {
+            *seen_fingerprint = Some(fingerprint);
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// returns the stream along with the fingerprint of the certificate the server presented
+pub fn connect(tcp: TcpStream, server_host: &str, pinned_fingerprint: Option<String>) -> Result<(Stream, String)> {
+    let verifier = Arc::new(PinnedCertificateVerifier {
+        provider: provider(),
+        pinned_fingerprint,
+        seen_fingerprint: Mutex::new(None),
+    });
+
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    // the certificate is pinned rather than checked against the name, so any valid name will do
+    let server_name = ServerName::try_from(server_host.to_string())
+        .unwrap_or_else(|_| ServerName::try_from("photo-server").expect("static server name is valid"));
+
+    let mut tls = StreamOwned::new(ClientConnection::new(Arc::new(config), server_name)?, tcp);
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+
+    let fingerprint = verifier.seen_fingerprint.lock()
+        .map_err(|e| anyhow::anyhow!("{}", e))?
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("server did not present a certificate"))?;
+    Ok((Stream::ClientTls(Box::new(tls)), fingerprint))
+}
@@ -0,0 +1,618 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::Path, time::SystemTime};
+use serde::{Deserialize, Serialize};
+
+// a tracked file, the tree keys it by its path relative to the repository root eg. DCIM/2024/IMG_0001.jpg
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub size: u64,
+    pub hash: String, // hex encoded sha256 of the file contents
+    pub created: SystemTime, // when the file was created on the device that uploaded it
+    pub uploaded: SystemTime,
+    pub file_location: String, // where the uploading device keeps the file
+}
+
+// one change to the tree, history holds these by version and both sides replay them in version order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TreeOperation {
+    Add { path: String, entry: FileEntry },
+    Modify { path: String, entry: FileEntry }, // the file at path was replaced with new contents
+    Remove { path: String }, // drops a file, or a directory and everything below it
+    Rename { from: String, to: String }, // moves a file, or a directory and everything below it
+}
+
+// a client side change to one repository, sent along with the uploads of a batch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TreeChange {
+    pub repo_name: String,
+    pub operation: TreeOperation,
+}
+
+// one history entry along with the version it was recorded at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionedOperation {
+    pub version: i32,
+    pub operation: TreeOperation,
+}
+
+// the server's answer to GetRepoTree, either the operations the client is missing or the whole tree
+// when its version can't be caught up from the history
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TreeSync {
+    Updates { head_version: i32, operations: Vec<VersionedOperation> }, // in version order, ending at head_version - 1
+    Snapshot(Tree), // the checkpoint plus the operations after it, call rebuild before reading files
+}
+
+// the files as they were at version, the history before it has been folded in and dropped
+#[derive(Serialize,Deserialize, Default, Debug, Clone)]
+pub struct TreeCheckpoint {
+    pub version: i32,
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+// operations are folded into the checkpoint once this many pile up past the retained ones
+const CHECKPOINT_INTERVAL: i32 = 256;
+// kept after a checkpoint so clients that are a little behind still get a diff instead of a snapshot
+const RETAINED_OPERATIONS: i32 = 1024;
+
+// the snapshot is rewritten and the journal cleared once this many operations have been journaled
+const SNAPSHOT_INTERVAL: usize = 128;
+
+// what replaying a tree's journal on top of its snapshot turned up
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalReplay {
+    pub replayed: usize,
+    pub skipped: usize, // already part of the snapshot
+    pub truncated: usize, // records cut short by a crash, they and everything after them are dropped
+}
+
+#[derive(Serialize,Deserialize, Default, Debug, Clone)]
+pub struct Tree {
+    pub version: i32,
+    #[serde(skip)]
+    pub files: BTreeMap<String, FileEntry>, // relative path -> file, rebuilt from the checkpoint and history
+    #[serde(default)]
+    pub checkpoint: TreeCheckpoint,
+    pub history: HashMap<i32, TreeOperation>, // the modifications since the checkpoint
+    pub path: String,
+    pub name: String,
+    #[serde(skip)]
+    pub journal_length: usize, // operations appended to the journal since the last snapshot
+}
+
+// true when path sits somewhere below directory, the empty directory is the repository root
+fn is_below(path:&str, directory:&str) -> bool {
+    directory.is_empty() || path.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// rebuilds a relative path from plain components so it can't climb out of the repository
+// the server stores uploads under this path and the client looks them up in the tree by it, so both have to use it
+pub fn sanitize_relative_path(relative_path: &str) -> Option<String> {
+    let mut components = Vec::<String>::new();
+    for component in relative_path.split(['/', '\\']) {
+        let component = component.trim().replace(|c: char| c.is_control() || c == ':', "_");
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => return None,
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return None;
+    }
+    Some(components.join("/"))
+}
+
+pub fn journal_path(tree_path:&str) -> String {
+    format!("{}.journal", tree_path)
+}
+
+// makes a rename of path durable, not every platform can open a directory so failures are ignored
+pub fn sync_directory(path:&str) {
+    if let Some(directory) = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty())
+        && let Ok(directory) = File::open(directory) {
+        directory.sync_all().ok();
+    }
+}
+
+fn renamed_paths(files:&BTreeMap<String, FileEntry>, from:&str, to:&str) -> Vec<(String, String)> {
+    if from.is_empty() {
+        return Vec::new();
+    }
+    files.keys()
+        .filter(|path| path.as_str() == from || is_below(path, from))
+        .map(|path| (path.clone(), format!("{}{}", to, &path[from.len()..])))
+        .collect()
+}
+
+// shared by the live files and the checkpoint so both fold operations the same way
+fn apply_operation(files:&mut BTreeMap<String, FileEntry>, operation:&TreeOperation) {
+    match operation {
+        TreeOperation::Add { path, entry } | TreeOperation::Modify { path, entry } => {
+            files.insert(path.clone(), entry.clone());
+        }
+        TreeOperation::Remove { path } => {
+            files.retain(|file_path, _| file_path != path && !is_below(file_path, path));
+        }
+        TreeOperation::Rename { from, to } => {
+            for (old_path, new_path) in renamed_paths(files, from, to) {
+                if let Some(entry) = files.remove(&old_path) {
+                    files.insert(new_path, entry);
+                }
+            }
+        }
+    }
+}
+
+impl Tree {
+    pub fn new(name:&str) -> Self {
+        Tree {
+            path: format!("trees/{}.tree", name),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // a missing or unreadable tree comes back as the default, clients rebuild theirs from the server's history
+    pub fn load_from_file(path: &str) -> Self {
+        Self::load_with_journal(path).map(|(tree, _)| tree).unwrap_or_else(|e| {
+            println!("Unable to load tree {}, using default tree. {}", path, e);
+            Tree::default()
+        })
+    }
+
+    // reads the last snapshot and replays the journal written since
+    // a snapshot that can't be parsed is an error and a copy is kept as .corrupt, the snapshot and the journal
+    // are left alone since an empty tree written over them would lose the only record of the repository
+    pub fn load_with_journal(path: &str) -> std::io::Result<(Self, JournalReplay)> {
+        let tree_content = std::fs::read_to_string(path)?;
+        let mut tree: Tree = serde_json::from_str(&tree_content).map_err(|e| {
+            if let Err(e) = std::fs::copy(path, format!("{}.corrupt", path)) {
+                eprintln!("Failed to keep a copy of {}. {}", path, e);
+            }
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} can't be parsed, a copy was kept as {}.corrupt. {}", path, path, e))
+        })?;
+
+        let replay = tree.replay_journal(&journal_path(path));
+        if replay.truncated > 0 {
+            eprintln!("{} had {} truncated journal records, they were dropped", path, replay.truncated);
+        }
+        tree.journal_length = replay.replayed + replay.skipped;
+        tree.rebuild();
+        Ok((tree, replay))
+    }
+
+    fn replay_journal(&mut self, journal_path: &str) -> JournalReplay {
+        let mut replay = JournalReplay::default();
+        let journal = match File::open(journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return replay,
+        };
+
+        let mut lines = BufReader::new(journal).split(b'\n');
+        for line in lines.by_ref() {
+            let record = line.ok().and_then(|line| serde_json::from_slice::<VersionedOperation>(&line).ok());
+            match record {
+                Some(record) if record.version < self.version => replay.skipped += 1,
+                Some(record) if record.version == self.version => {
+                    self.add_history(record.operation);
+                    replay.replayed += 1;
+                }
+                // a torn write or a gap, nothing after it can be trusted
+                _ => {
+                    replay.truncated = 1;
+                    break;
+                }
+            }
+        }
+        replay.truncated += lines.count();
+        replay
+    }
+
+    // writes the tree next to path and renames it over the old one, so a crash leaves one or the other intact
+    // the journal only holds what came after the previous snapshot, so it is cleared once this one is in place
+    pub fn save_to_file(&self, path: &str) {
+        if let Err(e) = self.write_snapshot(path) {
+            eprintln!("Failed to write tree file: {}", e);
+        }
+    }
+
+    fn write_snapshot(&self, path: &str) -> std::io::Result<()> {
+        let tree_content = serde_json::to_vec(self)?;
+        let temp_path = format!("{}.tmp", path);
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&tree_content)?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        sync_directory(path);
+
+        let journal_path = journal_path(path);
+        if Path::new(&journal_path).exists() {
+            File::create(&journal_path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // appends the operation recorded at version to the journal, the snapshot is only rewritten every
+    // so often or when compaction dropped history it still holds
+    // an error means the operation isn't on disk, once it's journaled a failed snapshot is only logged and tried again later
+    pub fn persist(&mut self, version: i32) -> std::io::Result<()> {
+        let operation = self.history.get(&version).cloned()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} has no version {}", self.name, version)))?;
+        let mut record = serde_json::to_vec(&VersionedOperation { version, operation })?;
+        record.push(b'\n');
+
+        let mut journal = OpenOptions::new().create(true).append(true).open(journal_path(&self.path))?;
+        let journal_end = journal.metadata()?.len();
+        if let Err(e) = journal.write_all(&record).and_then(|_| journal.sync_data()) {
+            // a torn record would make every record appended after it unreadable
+            journal.set_len(journal_end).ok();
+            return Err(e);
+        }
+        self.journal_length += 1;
+
+        let compacted = self.compact();
+        if compacted || self.journal_length >= SNAPSHOT_INTERVAL {
+            if compacted {
+                println!("checkpointed {} at version {}", self.name, self.checkpoint.version);
+            }
+            match self.write_snapshot(&self.path) {
+                Ok(()) => self.journal_length = 0,
+                Err(e) => eprintln!("Failed to write a snapshot of {}, its journal still holds every operation. {}", self.name, e),
+            }
+        }
+        Ok(())
+    }
+
+    // removes the snapshot and the journal
+    pub fn delete_files(path: &str) -> std::io::Result<()> {
+        std::fs::remove_file(path)?;
+        let journal_path = journal_path(path);
+        if Path::new(&journal_path).exists() {
+            std::fs::remove_file(journal_path)?;
+        }
+        Ok(())
+    }
+
+    // every tracked file a rename of from touches, paired with where it ends up
+    pub fn renamed_paths(&self, from:&str, to:&str) -> Vec<(String, String)> {
+        renamed_paths(&self.files, from, to)
+    }
+
+    // true when path is a tracked file or a directory holding one
+    pub fn contains(&self, path:&str) -> bool {
+        !path.is_empty() && self.files.keys().any(|file_path| file_path == path || is_below(file_path, path))
+    }
+
+    pub fn apply_history(&mut self, start_index:i32) {
+        for i in start_index..self.version {
+            if let Some(operation) = self.history.get(&i) {
+                apply_operation(&mut self.files, operation);
+            }
+        }
+    }
+
+    // replays the history on top of the checkpoint, files isn't stored so this runs whenever a tree is read in
+    pub fn rebuild(&mut self) {
+        self.files = self.checkpoint.files.clone();
+        self.apply_history(self.checkpoint.version);
+    }
+
+    // folds everything but the most recent operations into the checkpoint, returns true if anything was folded
+    pub fn compact(&mut self) -> bool {
+        if self.version - self.checkpoint.version < RETAINED_OPERATIONS + CHECKPOINT_INTERVAL {
+            return false;
+        }
+        let checkpoint_version = self.version - RETAINED_OPERATIONS;
+        for i in self.checkpoint.version..checkpoint_version {
+            if let Some(operation) = self.history.remove(&i) {
+                apply_operation(&mut self.checkpoint.files, &operation);
+            }
+        }
+        self.checkpoint.version = checkpoint_version;
+        true
+    }
+
+    pub fn add_history(&mut self, operation:TreeOperation) -> i32 {
+        self.history.insert(self.version, operation);
+        self.version += 1;
+        self.version - 1
+    }
+
+    // the operations that bring a tree at version up to this one, None when they can't be replayed from the history
+    pub fn updates_since(&self, version:i32) -> Option<Vec<VersionedOperation>> {
+        if version < self.checkpoint.version || version > self.version {
+            return None;
+        }
+        (version..self.version)
+            .map(|v| self.history.get(&v).map(|operation| VersionedOperation { version: v, operation: operation.clone() }))
+            .collect()
+    }
+
+    // replays updates from the server, refusing them unless they continue exactly from this tree's version
+    pub fn apply_updates(&mut self, head_version:i32, operations:Vec<VersionedOperation>) -> Result<(), String> {
+        if head_version < self.version {
+            return Err(format!("{} is at version {} but the server is only at {}", self.name, self.version, head_version));
+        }
+        if operations.len() != (head_version - self.version) as usize {
+            return Err(format!("{} expected {} updates to reach version {} but received {}",
+                self.name, head_version - self.version, head_version, operations.len()));
+        }
+        for (expected, update) in (self.version..).zip(&operations) {
+            if update.version != expected {
+                return Err(format!("{} expected version {} but received {}", self.name, expected, update.version));
+            }
+        }
+
+        let start_index = self.version;
+        for update in operations {
+            self.add_history(update.operation);
+        }
+        self.apply_history(start_index);
+        Ok(())
+    }
+
+    // every directory that holds a file somewhere below it
+    pub fn directories(&self) -> BTreeSet<String> {
+        let mut directories = BTreeSet::new();
+        for path in self.files.keys() {
+            let mut directory = path.as_str();
+            while let Some((parent, _)) = directory.rsplit_once('/') {
+                directories.insert(parent.to_string());
+                directory = parent;
+            }
+        }
+        directories
+    }
+
+    // the names directly inside directory, each flagged true when it's a directory
+    pub fn list_directory(&self, directory:&str) -> Vec<(String, bool)> {
+        let mut entries = BTreeMap::<String, bool>::new();
+        for path in self.files.keys().filter(|path| is_below(path, directory)) {
+            let rest = if directory.is_empty() { path.as_str() } else { &path[directory.len() + 1..] };
+            match rest.split_once('/') {
+                Some((name, _)) => { entries.insert(name.to_string(), true); },
+                None => { entries.entry(rest.to_string()).or_insert(false); },
+            }
+        }
+        entries.into_iter().collect()
+    }
+
+    // every tracked file that sits somewhere below the given directory
+    pub fn files_in_directory(&self, directory:&str) -> Vec<String> {
+        self.files.keys()
+            .filter(|path| is_below(path, directory))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a tree in its own scratch directory, removed again when the test is done
+    struct ScratchTree {
+        directory: std::path::PathBuf,
+        path: String,
+    }
+
+    impl ScratchTree {
+        fn new(test_name: &str) -> Self {
+            let directory = std::env::temp_dir().join(format!("photo-backup-{}-{}", test_name, std::process::id()));
+            std::fs::remove_dir_all(&directory).ok();
+            std::fs::create_dir_all(&directory).unwrap();
+            let path = directory.join("repo.tree").to_string_lossy().to_string();
+            ScratchTree { directory, path }
+        }
+
+        fn tree(&self) -> Tree {
+            Tree { path: self.path.clone(), name: "repo".to_string(), ..Default::default() }
+        }
+
+        fn append_journal(&self, bytes: &[u8]) {
+            OpenOptions::new().create(true).append(true).open(journal_path(&self.path)).unwrap().write_all(bytes).unwrap();
+        }
+    }
+
+    impl Drop for ScratchTree {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.directory).ok();
+        }
+    }
+
+    fn add(path: &str) -> TreeOperation {
+        TreeOperation::Add {
+            path: path.to_string(),
+            entry: FileEntry {
+                size: 1,
+                hash: "00".to_string(),
+                created: SystemTime::UNIX_EPOCH,
+                uploaded: SystemTime::UNIX_EPOCH,
+                file_location: path.to_string(),
+            },
+        }
+    }
+
+    fn record(version: i32, path: &str) -> Vec<u8> {
+        let mut record = serde_json::to_vec(&VersionedOperation { version, operation: add(path) }).unwrap();
+        record.push(b'\n');
+        record
+    }
+
+    #[test]
+    fn corrupt_snapshot_is_an_error_and_leaves_the_journal_alone() {
+        let scratch = ScratchTree::new("corrupt-snapshot");
+        std::fs::write(&scratch.path, b"{\"version\": 3, \"hist").unwrap();
+        scratch.append_journal(&record(3, "a.jpg"));
+
+        let error = Tree::load_with_journal(&scratch.path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&scratch.path).unwrap(), b"{\"version\": 3, \"hist");
+        assert_eq!(std::fs::read(format!("{}.corrupt", scratch.path)).unwrap(), b"{\"version\": 3, \"hist");
+        assert_eq!(std::fs::read(journal_path(&scratch.path)).unwrap(), record(3, "a.jpg"));
+    }
+
+    #[test]
+    fn missing_snapshot_is_an_error() {
+        let scratch = ScratchTree::new("missing-snapshot");
+        assert_eq!(Tree::load_with_journal(&scratch.path).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn truncated_journal_records_and_everything_after_them_are_dropped() {
+        let scratch = ScratchTree::new("truncated-journal");
+        scratch.tree().save_to_file(&scratch.path);
+        scratch.append_journal(&record(0, "a.jpg"));
+        scratch.append_journal(&record(1, "b.jpg"));
+        scratch.append_journal(&record(2, "c.jpg")[..20]);
+        scratch.append_journal(b"\n");
+        scratch.append_journal(&record(3, "d.jpg"));
+
+        let (tree, replay) = Tree::load_with_journal(&scratch.path).unwrap();
+        assert_eq!((replay.replayed, replay.skipped, replay.truncated), (2, 0, 2));
+        assert_eq!(tree.version, 2);
+        assert_eq!(tree.files.keys().collect::<Vec<&String>>(), ["a.jpg", "b.jpg"]);
+    }
+
+    #[test]
+    fn journal_records_already_in_the_snapshot_are_skipped() {
+        // the server stopped after the new snapshot was renamed into place but before the journal was cleared
+        let scratch = ScratchTree::new("snapshot-overlap");
+        let mut tree = scratch.tree();
+        for (version, path) in ["a.jpg", "b.jpg", "c.jpg"].into_iter().enumerate() {
+            tree.add_history(add(path));
+            scratch.append_journal(&record(version as i32, path));
+        }
+        let journal = std::fs::read(journal_path(&scratch.path)).unwrap();
+        tree.save_to_file(&scratch.path);
+        std::fs::write(journal_path(&scratch.path), journal).unwrap();
+        scratch.append_journal(&record(3, "d.jpg"));
+
+        let (tree, replay) = Tree::load_with_journal(&scratch.path).unwrap();
+        assert_eq!((replay.replayed, replay.skipped, replay.truncated), (1, 3, 0));
+        assert_eq!(tree.version, 4);
+        assert_eq!(tree.files.len(), 4);
+    }
+
+    #[test]
+    fn persisted_operations_survive_a_reload() {
+        let scratch = ScratchTree::new("persist");
+        let mut tree = scratch.tree();
+        tree.save_to_file(&scratch.path);
+        for path in ["a.jpg", "b.jpg"] {
+            let version = tree.add_history(add(path));
+            tree.persist(version).unwrap();
+        }
+
+        let (reloaded, replay) = Tree::load_with_journal(&scratch.path).unwrap();
+        assert_eq!(replay.replayed, 2);
+        assert_eq!(reloaded.version, 2);
+        assert_eq!(reloaded.files.len(), 2);
+    }
+
+    #[test]
+    fn sanitized_paths_are_joined_from_plain_components() {
+        assert_eq!(sanitize_relative_path("DCIM/a.jpg").as_deref(), Some("DCIM/a.jpg"));
+        assert_eq!(sanitize_relative_path("./DCIM//a.jpg").as_deref(), Some("DCIM/a.jpg"));
+        assert_eq!(sanitize_relative_path(" DCIM / a.jpg ").as_deref(), Some("DCIM/a.jpg"));
+    }
+
+    #[test]
+    fn sanitized_paths_cant_climb_out_of_the_repository() {
+        assert_eq!(sanitize_relative_path("../a.jpg"), None);
+        assert_eq!(sanitize_relative_path("DCIM/../../a.jpg"), None);
+        assert_eq!(sanitize_relative_path("DCIM\\..\\..\\a.jpg"), None);
+        assert_eq!(sanitize_relative_path(""), None);
+        assert_eq!(sanitize_relative_path("/./"), None);
+    }
+
+    #[test]
+    fn absolute_paths_are_kept_inside_the_repository() {
+        assert_eq!(sanitize_relative_path("/etc/passwd").as_deref(), Some("etc/passwd"));
+        assert_eq!(sanitize_relative_path("C:\\Users\\me\\a.jpg").as_deref(), Some("C_/Users/me/a.jpg"));
+        assert_eq!(sanitize_relative_path("\\\\server\\share\\a.jpg").as_deref(), Some("server/share/a.jpg"));
+    }
+
+    #[test]
+    fn backslashes_separate_components() {
+        assert_eq!(sanitize_relative_path("DCIM\\a.jpg").as_deref(), Some("DCIM/a.jpg"));
+    }
+
+    #[test]
+    fn control_characters_and_colons_are_replaced() {
+        assert_eq!(sanitize_relative_path("a\nb\u{7}.jpg").as_deref(), Some("a_b_.jpg"));
+        assert_eq!(sanitize_relative_path("12:30.jpg").as_deref(), Some("12_30.jpg"));
+        // sanitizing is idempotent, the client's lookups match what the server stored
+        assert_eq!(sanitize_relative_path("12_30.jpg").as_deref(), Some("12_30.jpg"));
+    }
+
+    // a server side tree holding DCIM/a.jpg, DCIM/b.jpg and c.jpg after a rename and a removal
+    fn server_tree() -> Tree {
+        let mut tree = Tree::new("repo");
+        for operation in [
+            add("a.jpg"),
+            add("b.jpg"),
+            add("c.jpg"),
+            TreeOperation::Remove { path: "b.jpg".to_string() },
+            add("DCIM/a.jpg"),
+            add("DCIM/b.jpg"),
+            TreeOperation::Rename { from: "a.jpg".to_string(), to: "DCIM/a.jpg".to_string() },
+        ] {
+            tree.add_history(operation);
+        }
+        tree.rebuild();
+        tree
+    }
+
+    #[test]
+    fn updates_bring_a_client_up_to_the_server() {
+        let server = server_tree();
+        for client_version in 0..=server.version {
+            let mut client = Tree::new("repo");
+            let updates = server.updates_since(0).unwrap();
+            client.apply_updates(client_version, updates[..client_version as usize].to_vec()).unwrap();
+
+            let updates = server.updates_since(client_version).unwrap();
+            assert_eq!(updates.len(), (server.version - client_version) as usize);
+            client.apply_updates(server.version, updates).unwrap();
+            assert_eq!(client.version, server.version);
+            assert_eq!(client.files, server.files);
+        }
+        assert_eq!(server.files.keys().collect::<Vec<_>>(), ["DCIM/a.jpg", "DCIM/b.jpg", "c.jpg"]);
+    }
+
+    #[test]
+    fn updates_since_a_version_the_server_cant_replay() {
+        let mut server = server_tree();
+        assert!(server.updates_since(server.version + 1).is_none());
+        assert_eq!(server.updates_since(server.version), Some(Vec::new()));
+
+        while !server.compact() {
+            server.add_history(add("d.jpg"));
+        }
+        assert!(server.updates_since(server.checkpoint.version - 1).is_none());
+        assert!(server.updates_since(server.checkpoint.version).is_some());
+    }
+
+    #[test]
+    fn updates_that_dont_continue_the_tree_are_refused() {
+        let server = server_tree();
+        let updates = server.updates_since(0).unwrap();
+        let mut client = Tree::new("repo");
+        client.apply_updates(2, updates[..2].to_vec()).unwrap();
+
+        // the server is behind the client
+        assert!(client.apply_updates(1, Vec::new()).is_err());
+        // too few updates to reach the head
+        assert!(client.apply_updates(server.version, updates[2..4].to_vec()).is_err());
+        // a gap in the versions
+        let mut gapped = updates[2..].to_vec();
+        gapped.remove(1);
+        assert!(client.apply_updates(server.version - 1, gapped).is_err());
+        // starting from a version the client already has
+        assert!(client.apply_updates(server.version - 1, updates[1..server.version as usize - 1].to_vec()).is_err());
+
+        assert_eq!(client.version, 2);
+        assert_eq!(client.files.len(), 2);
+    }
+}